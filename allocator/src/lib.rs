@@ -11,7 +11,7 @@ use page_table::entry::addr::VirtualAddr;
 use page_table::entry::perm::PTEPermission;
 use sbi_print::println;
 use spin::lazy::Lazy;
-use spin::{Mutex, MutexGuard};
+use spinlock::SpinLock;
 use page_table::PageTable;
 
 struct FreeMemoryNode {
@@ -24,13 +24,13 @@ struct MyAllocator {
     end_address: VirtualAddr,
     allocated: usize,
     nodes: Option<NonNull<FreeMemoryNode>>,
-    kernel_page_table: Option<&'static Mutex<&'static mut PageTable>>
+    kernel_page_table: Option<&'static SpinLock<&'static mut PageTable>>
 }
 
-struct MyGlobalAllocator(Lazy<Mutex<MyAllocator>>);
+struct MyGlobalAllocator(Lazy<SpinLock<MyAllocator>>);
 
 impl MyGlobalAllocator {
-    pub fn init(&mut self, start_addr: VirtualAddr, end_addr: VirtualAddr, kernel_page_table: &'static Mutex<&'static mut PageTable>) {
+    pub fn init(&mut self, start_addr: VirtualAddr, end_addr: VirtualAddr, kernel_page_table: &'static SpinLock<&'static mut PageTable>) {
         let mut alloc = self.0.lock();
         alloc.kernel_page_table = Some(kernel_page_table);
         alloc.start_address = start_addr;
@@ -202,7 +202,7 @@ unsafe impl GlobalAlloc for MyGlobalAllocator {
 
 #[global_allocator]
 static mut ALLOCATOR: MyGlobalAllocator = MyGlobalAllocator(Lazy::new(|| {
-    Mutex::new(MyAllocator {
+    SpinLock::new("heap", MyAllocator {
         start_address: VirtualAddr::new(0),
         end_address: VirtualAddr::new(0),
         allocated: 0,
@@ -211,7 +211,7 @@ static mut ALLOCATOR: MyGlobalAllocator = MyGlobalAllocator(Lazy::new(|| {
     })
 }));
 
-pub fn init_heap(kernel_page_table: &'static Mutex<&'static mut PageTable>) {
+pub fn init_heap(kernel_page_table: &'static SpinLock<&'static mut PageTable>) {
     println!("Init heap");
 
     unsafe {
@@ -7,4 +7,5 @@ fn main() {
     println!("cargo:rerun-if-changed=src/asm/kernelvec.S");
     println!("cargo:rerun-if-changed=src/asm/switch.S");
     println!("cargo:rerun-if-changed=src/asm/trampoline.S");
+    println!("cargo:rerun-if-changed=src/asm/uaccess.S");
 }
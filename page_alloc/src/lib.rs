@@ -7,7 +7,7 @@ pub mod physical_memory_manager;
 
 use core::alloc::AllocError;
 use core::ptr::NonNull;
-use spin::Mutex;
+use spinlock::SpinLock;
 
 #[derive(Debug, Copy, Clone)]
 pub struct MyMemoryRegion {
@@ -38,11 +38,12 @@ struct PageAllocator {
     start: usize,
     end: usize,
     node: Option<NonNull<Node>>,
+    free_pages: usize,
 }
 // TODO : It may be a bad idea
 unsafe impl Send for PageAllocator {}
 
-pub struct StaticPageAllocator(Mutex<PageAllocator>);
+pub struct StaticPageAllocator(SpinLock<PageAllocator>);
 
 impl PageAllocator {
     fn init(&mut self, free_memory_region: MyMemoryRegion) {
@@ -51,6 +52,7 @@ impl PageAllocator {
         self.start = start_memory_addr as usize;
         let mut old_node = NonNull::new(start_memory_addr as *mut Node).unwrap();
         self.node = Some(old_node); // Set the First Node
+        self.free_pages = 0; // The loop also goes through the first node
         unsafe {
             old_node.as_mut().next = None;
         }
@@ -64,15 +66,17 @@ impl PageAllocator {
                 old_node.as_mut().next = Some(next_node);
             }
             old_node = next_node;
+            self.free_pages += 1;
         }
         self.end = usize::from(old_node.addr());
     }
 }
 
-pub static PAGE_ALLOCATOR: StaticPageAllocator = StaticPageAllocator(Mutex::new(PageAllocator {
+pub static PAGE_ALLOCATOR: StaticPageAllocator = StaticPageAllocator(SpinLock::new("page_allocator", PageAllocator {
     start: 0,
     end: 0,
     node: None,
+    free_pages: 0,
 }));
 
 impl StaticPageAllocator {
@@ -84,12 +88,18 @@ impl StaticPageAllocator {
         self.0.lock().end
     }
 
+    // Used by the swap to know when to reclaim pages
+    pub fn free_count(&self) -> usize {
+        self.0.lock().free_pages
+    }
+
     pub fn kalloc(&self) -> Result<NonNull<u8>, AllocError> {
         let mut alloc = self.0.lock();
         let first_node_addr = alloc.node.ok_or(AllocError)?;
         unsafe {
             alloc.node = first_node_addr.as_ref().next;
         }
+        alloc.free_pages -= 1;
 
         unsafe {
             memset(first_node_addr.cast(), PAGE_SIZE, 0);
@@ -106,8 +116,9 @@ impl StaticPageAllocator {
         let mut new_node: NonNull<Node> = physical_address.cast();
         unsafe {
             new_node.as_mut().next = alloc.node;
-            alloc.node = new_node.as_ref().next;
+            alloc.node = Some(new_node);
         }
+        alloc.free_pages += 1;
     }
 }
 
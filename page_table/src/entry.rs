@@ -5,11 +5,20 @@ use bit_field::BitField;
 use core::ops::{BitAnd, BitOr, BitOrAssign};
 use perm::PTEPermission;
 use crate::entry::addr::{PageOffset, PhysicalAddr, Ppn};
-use crate::entry::perm::{PTE_BIT_EXECUTE, PTE_BIT_READ, PTE_BIT_VALID, PTE_BIT_WRITE};
+use crate::entry::perm::{
+    PTE_BIT_ACCESSED, PTE_BIT_EXECUTE, PTE_BIT_READ, PTE_BIT_USER, PTE_BIT_VALID, PTE_BIT_WRITE,
+};
 
 pub mod addr;
 pub mod perm;
 
+// Set in the RSW bits of an entry pointing to a page table owned by another page table
+pub const PTE_RSW_SHARED: u8 = 1;
+// Set in the RSW bits of a non valid entry keeping a page the user cannot access (PROT_NONE)
+pub const PTE_RSW_NO_ACCESS: u8 = 2;
+// Set in the RSW bits of a non valid entry whose page has been written to the swap, the slot replaces the PPN
+pub const PTE_RSW_SWAPPED: u8 = 3;
+
 #[derive(Debug, Clone)]
 pub struct PageTableEntry(pub u64);
 
@@ -17,6 +26,9 @@ pub struct PageTableEntry(pub u64);
 pub(super) enum EntryKind {
     Leaf,
     Branch(PhysicalAddr),
+    // A leaf without any permission, not valid for the MMU
+    NoAccess,
+    Swapped(u64),
     NotValid,
 }
 
@@ -43,6 +55,10 @@ impl PageTableEntry {
         Self(0)
     }
 
+    pub fn new_swapped(slot: u64) -> Self {
+        Self::new(Ppn(slot), PTE_RSW_SWAPPED, PTEPermission::new())
+    }
+
     pub fn is_valid(&self) -> bool {
         self.0.get_bit(PTE_BIT_VALID)
     }
@@ -59,15 +75,42 @@ impl PageTableEntry {
         self.0.get_bit(PTE_BIT_EXECUTE)
     }
 
+    pub fn is_user(&self) -> bool {
+        self.0.get_bit(PTE_BIT_USER)
+    }
+
+    // Set by the MMU when the page is used
+    pub fn is_accessed(&self) -> bool {
+        self.0.get_bit(PTE_BIT_ACCESSED)
+    }
+
+    pub fn clear_accessed(&mut self) {
+        self.0.set_bit(PTE_BIT_ACCESSED, false);
+    }
+
     pub fn is_zero(&self) -> bool {
         self.0 == 0
     }
 
+    pub fn rsw(&self) -> u8 {
+        self.0.get_bits(8..10) as u8
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.rsw() == PTE_RSW_SHARED
+    }
+
     pub fn perm(&self) -> PTEPermission {
         PTEPermission(self.0.get_bits(0..8) as u8)
     }
 
     pub(super) fn kind(&self) -> EntryKind {
+        if !self.is_valid() && self.rsw() == PTE_RSW_NO_ACCESS {
+            return EntryKind::NoAccess;
+        }
+        if !self.is_valid() && self.rsw() == PTE_RSW_SWAPPED {
+            return EntryKind::Swapped(self.ppn().0);
+        }
         if (!self.is_valid()) || (!self.is_valid() && self.is_write()) {
             return EntryKind::NotValid;
         }
@@ -78,7 +121,7 @@ impl PageTableEntry {
         EntryKind::Branch(pa)
     }
 
-    fn addr_zero_offset(&self) -> PhysicalAddr {
+    pub(super) fn addr_zero_offset(&self) -> PhysicalAddr {
         self.convert_to_physical_addr(&PageOffset(0))
     }
 
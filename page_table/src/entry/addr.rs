@@ -69,6 +69,10 @@ impl PhysicalAddr {
         assert!(val < MAX_PHYSICAL_ADDR);
         Self(val)
     }
+
+    pub fn get(&self) -> &u64 {
+        &self.0
+    }
 }
 
 #[derive(Debug)]
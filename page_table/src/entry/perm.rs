@@ -6,8 +6,9 @@ pub const PTE_BIT_READ: usize = 1;
 pub const PTE_BIT_WRITE: usize = 2;
 pub const PTE_BIT_EXECUTE: usize = 3;
 pub const PTE_BIT_USER: usize = 4;
+pub const PTE_BIT_ACCESSED: usize = 6;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone)]
 pub struct PTEPermission(pub u8);
 
 impl PTEPermission {
@@ -47,6 +48,10 @@ impl PTEPermission {
     }
 
     // TODO : set_global (maybe set_dirty and set_access)
+
+    pub fn contains(&self, perm: PTEPermission) -> bool {
+        self.0 & perm.0 == perm.0
+    }
 }
 
 impl BitOr for PTEPermission {
@@ -1,8 +1,9 @@
 #![no_std]
 
+use core::ptr::NonNull;
 use entry::addr::{PhysicalAddr, VirtualAddr, VirtualPageNumber};
 use page_alloc::{PAGE_ALLOCATOR, PAGE_SIZE};
-use crate::entry::{EntryKind, PageTableEntry};
+use crate::entry::{EntryKind, PageTableEntry, PTE_RSW_NO_ACCESS, PTE_RSW_SHARED};
 use crate::entry::perm::PTEPermission;
 
 pub mod entry;
@@ -10,10 +11,20 @@ pub mod entry;
 // 4096 bytes (PAGE_SIZE) / 8 bytes (64 bits) per entry = 512 entries
 const ENTRY_COUNT: u16 = 512;
 
+// The PAGE_ALLOCATOR has no page left for a new level of page table
+#[derive(Debug)]
+pub struct OutOfMemory;
+
 #[derive(Debug)]
 #[repr(align(4096))]
 pub struct PageTable([PageTableEntry; ENTRY_COUNT as usize]);
 
+impl Default for PageTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PageTable {
     pub const fn new() -> Self {
         const ZERO_ENTRY: PageTableEntry = PageTableEntry(0);
@@ -45,26 +56,64 @@ impl PageTable {
                     let new_page_table = unsafe { &*(page_table_addr.0 as *const PageTable) };
                     page_table = new_page_table;
                 }
-                EntryKind::NotValid => panic!("IMPOSSIBLE"),
+                EntryKind::NoAccess | EntryKind::Swapped(_) | EntryKind::NotValid => panic!("IMPOSSIBLE"),
             }
         }
 
         panic!("IMPOSSIBLE")
     }
 
+    // Same as get_phys_addr_perm but returns None if the address is not mapped
+    pub fn translate(&self, va: &VirtualAddr) -> Option<(PhysicalAddr, PTEPermission)> {
+        let page_numbers = va.virtual_page_numbers().into_iter().rev();
+        let mut page_table = self;
+
+        for vpn in page_numbers {
+            let entry = page_table.get_entry(vpn);
+            match entry.kind() {
+                EntryKind::Leaf => {
+                    return Some((
+                        entry.convert_to_physical_addr(&va.page_offset()),
+                        entry.perm(),
+                    ));
+                }
+                EntryKind::Branch(page_table_addr) => {
+                    page_table = unsafe { &*(page_table_addr.0 as *const PageTable) };
+                }
+                EntryKind::NoAccess | EntryKind::Swapped(_) | EntryKind::NotValid => return None,
+            }
+        }
+
+        None
+    }
+
     pub fn map_pages(
+        &mut self,
+        va: VirtualAddr,
+        pa: PhysicalAddr,
+        size: usize,
+        perm: PTEPermission,
+        rsw: u8,
+    ) {
+        self.try_map_pages(va, pa, size, perm, rsw)
+            .expect("No memory left for the page tables");
+    }
+
+    // Same as map_pages but fails if a page table cannot be allocated
+    // The pages mapped before the failure stay mapped
+    pub fn try_map_pages(
         &mut self,
         mut va: VirtualAddr,
         mut pa: PhysicalAddr,
         size: usize,
         perm: PTEPermission,
         _rsw: u8,
-    ) {
+    ) -> Result<(), OutOfMemory> {
         assert!(size > 0);
         let va_end = va.add_offset(size as u64).page_round_up();
 
         while va != va_end {
-            let page_table_entry_leaf = self.walk_alloc(&va);
+            let page_table_entry_leaf = self.try_walk_alloc(&va)?;
             // assert!(!page_table_entry_leaf.is_valid());
             // assert!(page_table_entry_leaf.is_zero());
             *page_table_entry_leaf =
@@ -72,31 +121,187 @@ impl PageTable {
             pa.0 += PAGE_SIZE as u64;
             va = va.add_offset(PAGE_SIZE as u64);
         }
+        Ok(())
+    }
+
+    // Unmap the pages in [va, va + size), the pages not mapped are skipped
+    // If `free` is set the physical pages are given back to the PAGE_ALLOCATOR
+    pub fn unmap_pages(&mut self, mut va: VirtualAddr, size: usize, free: bool) {
+        assert!(size > 0);
+        assert!(va.is_align(PAGE_SIZE as u64));
+        let va_end = va.add_offset(size as u64).page_round_up();
+
+        while va != va_end {
+            if let Some(entry) = self.walk(&va) {
+                if matches!(entry.kind(), EntryKind::Leaf | EntryKind::NoAccess) {
+                    if free {
+                        free_page(entry.addr_zero_offset());
+                    }
+                    *entry = PageTableEntry::new_zero();
+                }
+            }
+            va = va.add_offset(PAGE_SIZE as u64);
+        }
+    }
+
+    // Change the permissions of the pages mapped in [va, va + size), the pages not mapped are skipped
+    // Without read, write nor execute the page is kept in a non valid entry (see PTE_RSW_NO_ACCESS)
+    pub fn protect_pages(&mut self, mut va: VirtualAddr, size: usize, perm: PTEPermission) {
+        assert!(size > 0);
+        assert!(va.is_align(PAGE_SIZE as u64));
+        let va_end = va.add_offset(size as u64).page_round_up();
+        let accessible = perm.contains(PTEPermission::read()) || perm.contains(PTEPermission::execute());
+
+        while va != va_end {
+            if let Some(entry) = self.walk(&va) {
+                if matches!(entry.kind(), EntryKind::Leaf | EntryKind::NoAccess) {
+                    let ppn = entry.addr_zero_offset().ppn();
+                    *entry = if accessible {
+                        PageTableEntry::new(ppn, 0, PTEPermission::valid() | perm)
+                    } else {
+                        PageTableEntry::new(ppn, PTE_RSW_NO_ACCESS, perm)
+                    };
+                }
+            }
+            va = va.add_offset(PAGE_SIZE as u64);
+        }
+    }
+
+    // Returns true if the page at `va` has been used since the last call (false if it is not mapped)
+    pub fn test_and_clear_accessed(&mut self, va: &VirtualAddr) -> bool {
+        match self.walk(va) {
+            Some(entry) if entry.kind() == EntryKind::Leaf => {
+                let accessed = entry.is_accessed();
+                entry.clear_accessed();
+                accessed
+            }
+            _ => false,
+        }
+    }
+
+    // Replace the mapping of `va` by the swap slot where its page has been written, returns the page to free
+    pub fn set_swapped(&mut self, va: &VirtualAddr, slot: u64) -> Option<PhysicalAddr> {
+        let entry = self.walk(va)?;
+        if entry.kind() != EntryKind::Leaf {
+            return None;
+        }
+        let pa = entry.addr_zero_offset();
+        *entry = PageTableEntry::new_swapped(slot);
+        Some(pa)
+    }
+
+    // The swap slot of `va` if its page is in the swap
+    pub fn swapped_slot(&mut self, va: &VirtualAddr) -> Option<u64> {
+        match self.walk(va)?.kind() {
+            EntryKind::Swapped(slot) => Some(slot),
+            _ => None,
+        }
+    }
+
+    // Clear the entry of `va` if its page is in the swap and returns the slot
+    pub fn take_swapped(&mut self, va: &VirtualAddr) -> Option<u64> {
+        let entry = self.walk(va)?;
+        let EntryKind::Swapped(slot) = entry.kind() else {
+            return None;
+        };
+        *entry = PageTableEntry::new_zero();
+        Some(slot)
+    }
+
+    // Clear every entry of a page in the swap and call `f` with its slot
+    pub fn drain_swapped(&mut self, f: &mut impl FnMut(u64)) {
+        for entry in self.0.iter_mut().filter(|entry| !entry.is_shared()) {
+            match entry.kind() {
+                EntryKind::Swapped(slot) => {
+                    f(slot);
+                    *entry = PageTableEntry::new_zero();
+                }
+                EntryKind::Branch(page_table_addr) => {
+                    let page_table = unsafe { &mut *(page_table_addr.0 as *mut PageTable) };
+                    page_table.drain_swapped(f);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Unmap and free every page accessible from user mode
+    pub fn free_user_pages(&mut self) {
+        for entry in self.0.iter_mut().filter(|entry| !entry.is_shared()) {
+            match entry.kind() {
+                EntryKind::Leaf | EntryKind::NoAccess => {
+                    if entry.is_user() {
+                        free_page(entry.addr_zero_offset());
+                        *entry = PageTableEntry::new_zero();
+                    }
+                }
+                EntryKind::Branch(page_table_addr) => {
+                    let page_table = unsafe { &mut *(page_table_addr.0 as *mut PageTable) };
+                    page_table.free_user_pages();
+                }
+                // The swap slots must have been given back before (see drain_swapped)
+                EntryKind::Swapped(_) => *entry = PageTableEntry::new_zero(),
+                EntryKind::NotValid => {}
+            }
+        }
+    }
+
+    // Make the root entries covering [va_start, va_end) point to the same page tables as in `other`
+    // Used to map the kernel in the user page tables, the shared page tables are never freed from here
+    // Only the root entries existing now are copied: a later mapping in `other` is seen through a shared
+    // page table but a new root entry of `other` is not
+    pub fn share_root_entries(&mut self, other: &PageTable, va_start: VirtualAddr, va_end: VirtualAddr) {
+        let start = va_start.virtual_page_numbers()[2].0 as usize;
+        let end = va_end.sub_offset(1).virtual_page_numbers()[2].0 as usize;
+        for index in start..=end {
+            let entry = &other.0[index];
+            if let EntryKind::Branch(page_table_addr) = entry.kind() {
+                self.0[index] = PageTableEntry::new(page_table_addr.ppn(), PTE_RSW_SHARED, entry.perm());
+            }
+        }
     }
 
-    // // TODO : Unmap only one page for now (should do more)
-    // pub fn unmap_pages(&mut self, mut va: VirtualAddr) {
-    //     let page_table_entry_leaf = self.walk_alloc(&va);
-    //     assert!(page_table_entry_leaf.is_valid());
-    //     *page_table_entry_leaf = PageTableEntry::new_zero();
-    // }
+    // Same as walk_alloc but does not allocate the missing page tables
+    pub fn walk(&mut self, va: &VirtualAddr) -> Option<&mut PageTableEntry> {
+        let mut page_numbers = va.virtual_page_numbers().into_iter().rev();
+        let mut page_table = self;
+        let mut entry = page_table.get_entry_mut(page_numbers.next().unwrap());
+
+        for vpn in page_numbers {
+            match entry.kind() {
+                EntryKind::Leaf | EntryKind::NoAccess | EntryKind::Swapped(_) => break,
+                EntryKind::Branch(page_table_addr) => {
+                    let new_page_table = unsafe { &mut *(page_table_addr.0 as *mut PageTable) };
+                    page_table = new_page_table;
+                }
+                EntryKind::NotValid => return None,
+            }
+            entry = page_table.get_entry_mut(vpn);
+        }
+
+        Some(entry)
+    }
 
     pub fn walk_alloc(&mut self, va: &VirtualAddr) -> &mut PageTableEntry {
+        self.try_walk_alloc(va).expect("No memory left for the page tables")
+    }
+
+    pub fn try_walk_alloc(&mut self, va: &VirtualAddr) -> Result<&mut PageTableEntry, OutOfMemory> {
         let mut page_numbers = va.virtual_page_numbers().into_iter().rev();
         let mut page_table = self;
         let mut entry = page_table.get_entry_mut(page_numbers.next().unwrap());
 
         for vpn in page_numbers {
             match entry.kind() {
-                EntryKind::Leaf => break,
+                EntryKind::Leaf | EntryKind::NoAccess | EntryKind::Swapped(_) => break,
                 EntryKind::Branch(page_table_addr) => {
                     let new_page_table = unsafe { &mut *(page_table_addr.0 as *mut PageTable) };
                     page_table = new_page_table;
                 }
                 EntryKind::NotValid => {
                     // Allocate a page for a new PageTable
-                    let new_page_table_addr = PAGE_ALLOCATOR.kalloc().unwrap().cast().as_ptr();
-                    let new_page_table = unsafe { &mut *(new_page_table_addr as *mut PageTable) };
+                    let new_page_table_addr = PAGE_ALLOCATOR.kalloc().map_err(|_| OutOfMemory)?.cast::<PageTable>().as_ptr();
+                    let new_page_table = unsafe { &mut *new_page_table_addr };
                     *entry = PageTableEntry::new(
                         PhysicalAddr(new_page_table_addr as u64).ppn(),
                         0,
@@ -108,8 +313,31 @@ impl PageTable {
             entry = page_table.get_entry_mut(vpn);
         }
 
-        entry
+        Ok(entry)
     }
 }
 
-// TODO : Add free pagetable with impl Drop
+impl PageTable {
+    // Free the page tables pointed by this page table (but not the pages mapped nor the shared page tables)
+    fn free_branches(&mut self) {
+        for entry in self.0.iter_mut().filter(|entry| !entry.is_shared()) {
+            if let EntryKind::Branch(page_table_addr) = entry.kind() {
+                let page_table = unsafe { &mut *(page_table_addr.0 as *mut PageTable) };
+                page_table.free_branches();
+                free_page(page_table_addr);
+                *entry = PageTableEntry::new_zero();
+            }
+        }
+    }
+}
+
+// Only the root page table can be dropped, the other levels are pages from the PAGE_ALLOCATOR
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        self.free_branches();
+    }
+}
+
+fn free_page(pa: PhysicalAddr) {
+    PAGE_ALLOCATOR.kfree(NonNull::new(pa.0 as *mut u8).unwrap());
+}
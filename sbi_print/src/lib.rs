@@ -1,13 +1,122 @@
 #![no_std]
 
-fn sbi_print_str(s: &str) {
-    for c in s.bytes() {
-        if c.is_ascii() {
-            sbi::legacy::console_putchar(c);
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use spinlock::{hart_id, pop_off, push_off};
+
+// Where the output goes once a console driver is initialised, SBI is used until then
+static CONSOLE_OUTPUT: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+pub fn set_console_output(output: fn(&[u8])) {
+    CONSOLE_OUTPUT.store(output as *mut (), Ordering::Release);
+}
+
+// Print with SBI again, for the panic handler: the console driver takes a SpinLock and a panic of
+// lockdep (or one with the driver locked) would recurse or deadlock while printing
+pub fn reset_console_output() {
+    CONSOLE_OUTPUT.store(core::ptr::null_mut(), Ordering::Release);
+}
+
+// The bytes are given unchanged to the console, they do not have to be UTF-8
+fn write_bytes(bytes: &[u8]) {
+    let output = CONSOLE_OUTPUT.load(Ordering::Acquire);
+    if output.is_null() {
+        sbi_write_bytes(bytes);
+    } else {
+        let output: fn(&[u8]) = unsafe { core::mem::transmute(output) };
+        output(bytes);
+    }
+}
+
+// Print raw bytes (the echo and the output of user programs), serialised with print!
+pub fn print_bytes(bytes: &[u8]) {
+    let locked = lock_console();
+    write_bytes(bytes);
+    unlock_console(locked);
+}
+
+// The SBI Debug Console extension (DBCN) writes a whole buffer in one ecall
+const DBCN_EXTENSION_ID: usize = 0x4442434E;
+const DBCN_CONSOLE_WRITE: usize = 0;
+
+const DBCN_UNKNOWN: u8 = 0;
+const DBCN_AVAILABLE: u8 = 1;
+const DBCN_UNAVAILABLE: u8 = 2;
+
+static DBCN: AtomicU8 = AtomicU8::new(DBCN_UNKNOWN);
+
+fn dbcn_available() -> bool {
+    match DBCN.load(Ordering::Relaxed) {
+        DBCN_AVAILABLE => true,
+        DBCN_UNAVAILABLE => false,
+        _ => {
+            let available = sbi::base::probe_extension(DBCN_EXTENSION_ID).is_available();
+            let state = if available { DBCN_AVAILABLE } else { DBCN_UNAVAILABLE };
+            DBCN.store(state, Ordering::Relaxed);
+            available
         }
     }
 }
 
+// DBCN takes a physical address, the string is copied in a static buffer because the kernel data is
+// identity mapped (unlike the kernel heap)
+// Only used with CONSOLE_LOCK held
+static mut DBCN_BUFFER: [u8; 256] = [0; 256];
+
+fn sbi_write_bytes(bytes: &[u8]) {
+    if !dbcn_available() {
+        // The bytes are given unchanged, the terminal decodes the UTF-8
+        bytes.iter().copied().for_each(sbi::legacy::console_putchar);
+        return;
+    }
+
+    let buffer = unsafe { &mut *core::ptr::addr_of_mut!(DBCN_BUFFER) };
+    for chunk in bytes.chunks(buffer.len()) {
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let mut written = 0;
+        while written < chunk.len() {
+            let addr = buffer[written..].as_ptr() as usize;
+            let res = unsafe {
+                sbi::ecall3(chunk.len() - written, addr, 0, DBCN_EXTENSION_ID, DBCN_CONSOLE_WRITE)
+            };
+            match res {
+                Ok(n) => written += n,
+                // Nowhere to report it
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+// Held while printing so that the output of the harts does not interleave
+// The interrupts are disabled and the hart holding it can print again (an interrupt handler or a panic
+// while printing), it does not use spinlock::SpinLock so that lockdep can print its panic
+// (the panic handler also goes back to SBI, see reset_console_output)
+const NO_OWNER: usize = usize::MAX;
+static CONSOLE_LOCK: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+// Returns false if the hart already held the lock
+fn lock_console() -> bool {
+    push_off();
+    let hart_id = hart_id();
+    if CONSOLE_LOCK.load(Ordering::Relaxed) == hart_id {
+        return false;
+    }
+    while CONSOLE_LOCK
+        .compare_exchange_weak(NO_OWNER, hart_id, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn unlock_console(locked: bool) {
+    if locked {
+        CONSOLE_LOCK.store(NO_OWNER, Ordering::Release);
+    }
+    pop_off();
+}
+
 #[macro_export]
 macro_rules! print {
     // ($($arg:tt)*) => ($crate::sbi_print::_print(format_args!($($arg)*)));
@@ -24,7 +133,7 @@ pub struct ConsoleWriter;
 
 impl core::fmt::Write for ConsoleWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        sbi_print_str(s);
+        write_bytes(s.as_bytes());
         Ok(())
     }
 }
@@ -32,5 +141,7 @@ impl core::fmt::Write for ConsoleWriter {
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
+    let locked = lock_console();
     ConsoleWriter.write_fmt(args).unwrap();
+    unlock_console(locked);
 }
@@ -0,0 +1,41 @@
+// Compare the policies on a synthetic workload
+// Run on the host with `cargo make simulate`
+
+use sched_policy::sim::{simulate, TaskSpec, TaskStats};
+use sched_policy::{Fifo, Mlfq};
+
+const TIME_SLICE: usize = 5;
+const MAX_TICKS: u64 = 10_000;
+
+fn main() {
+    let workload = [
+        TaskSpec { name: "hog", nice: 0, arrival: 0, work: 400, burst: 0, block: 0 },
+        TaskSpec { name: "hog2", nice: 0, arrival: 0, work: 400, burst: 0, block: 0 },
+        TaskSpec { name: "niced hog", nice: 10, arrival: 0, work: 400, burst: 0, block: 0 },
+        TaskSpec { name: "interactive", nice: 0, arrival: 10, work: 50, burst: 1, block: 5 },
+        TaskSpec { name: "late short", nice: 0, arrival: 200, work: 20, burst: 0, block: 0 },
+    ];
+
+    print_stats("FIFO", &simulate::<Fifo<_>>(TIME_SLICE, &workload, MAX_TICKS));
+    print_stats("MLFQ", &simulate::<Mlfq<_>>(TIME_SLICE, &workload, MAX_TICKS));
+}
+
+fn print_stats(policy: &str, stats: &[TaskStats]) {
+    println!("{}", policy);
+    println!("{:<12} {:>9} {:>11} {:>6} {:>6}", "task", "response", "turnaround", "wait", "runs");
+    for task in stats {
+        println!(
+            "{:<12} {:>9} {:>11} {:>6} {:>6}",
+            task.name,
+            display(task.response),
+            display(task.turnaround),
+            task.wait,
+            task.runs
+        );
+    }
+    println!();
+}
+
+fn display(ticks: Option<u64>) -> String {
+    ticks.map_or(String::from("-"), |ticks| ticks.to_string())
+}
@@ -0,0 +1,92 @@
+use crate::{SchedPolicy, Task};
+use alloc::collections::VecDeque;
+
+// Round robin: every task gets the same time slice and runs in FIFO order, the nice value is ignored
+pub struct Fifo<T> {
+    queue: VecDeque<T>,
+    time_slice: usize,
+}
+
+impl<T: Task> SchedPolicy<T> for Fifo<T> {
+    fn new(time_slice: usize) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            time_slice,
+        }
+    }
+
+    fn enqueue(&mut self, task: T) {
+        self.queue.push_back(task);
+    }
+
+    fn pick_next(&mut self, _now: u64) -> Option<T> {
+        self.queue.pop_front()
+    }
+
+    // The last task as it would wait the longest here
+    fn steal(&mut self, cpu_id: usize) -> Option<T> {
+        let i = self.queue.iter().rposition(|task| task.sched_info().can_run_on(cpu_id))?;
+        self.queue.remove(i)
+    }
+
+    fn remove(&mut self, f: impl Fn(&T) -> bool) -> Option<T> {
+        let i = self.queue.iter().position(f)?;
+        self.queue.remove(i)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn on_tick(&mut self, task: &mut T) -> bool {
+        let info = task.sched_info_mut();
+        info.ticks += 1;
+        info.ticks >= self.time_slice
+    }
+
+    fn on_block(&mut self, _task: &mut T) {}
+
+    fn on_wake(&mut self, _task: &mut T) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTask;
+
+    fn ids(fifo: &mut Fifo<TestTask>) -> alloc::vec::Vec<usize> {
+        core::iter::from_fn(|| fifo.pick_next(0)).map(|task| task.id).collect()
+    }
+
+    #[test]
+    fn picks_in_enqueue_order() {
+        let mut fifo = Fifo::new(3);
+        for id in 0..3 {
+            // The nice value is ignored
+            fifo.enqueue(TestTask::new(id, -(id as i32)));
+        }
+        let mut first = fifo.pick_next(0).unwrap();
+        assert_eq!(first.id, 0);
+        // Preempted at the end of its time slice, it goes after the others
+        assert!(!fifo.on_tick(&mut first));
+        assert!(!fifo.on_tick(&mut first));
+        assert!(fifo.on_tick(&mut first));
+        fifo.enqueue(first);
+        assert_eq!(ids(&mut fifo), [1, 2, 0]);
+    }
+
+    #[test]
+    fn steal_and_remove() {
+        let mut fifo = Fifo::new(1);
+        for id in 0..4 {
+            let mut task = TestTask::new(id, 0);
+            task.sched.affinity = if id == 3 { 0b1 } else { 0b11 };
+            fifo.enqueue(task);
+        }
+        // The last task which can run on the hart 1
+        assert_eq!(fifo.steal(1).unwrap().id, 2);
+        assert_eq!(fifo.remove(|task| task.id == 1).unwrap().id, 1);
+        assert!(fifo.remove(|task| task.id == 1).is_none());
+        assert_eq!(ids(&mut fifo), [0, 3]);
+    }
+}
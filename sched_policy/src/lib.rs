@@ -0,0 +1,130 @@
+#![no_std]
+
+// The scheduling policies decide which task runs next and for how long, the context switch is done by the kernel
+// They do not depend on the kernel so they can also be run on the host (see `sim`)
+
+extern crate alloc;
+
+mod fifo;
+mod mlfq;
+pub mod sim;
+
+pub use fifo::Fifo;
+pub use mlfq::Mlfq;
+
+// Range of the nice values, a lower value gives a higher priority
+pub const MIN_NICE: i32 = -20;
+pub const MAX_NICE: i32 = 19;
+
+// Every hart
+pub const ALL_CPUS: u64 = u64::MAX;
+
+// What the policies know about a task, each policy only uses the fields it needs
+#[derive(Debug, Clone)]
+pub struct SchedInfo {
+    // Between MIN_NICE and MAX_NICE
+    pub nice: i32,
+    // Bit n is set if the task can run on the hart n
+    pub affinity: u64,
+    // Timer ticks since the task has been picked, reset by the caller of `pick_next`
+    pub ticks: usize,
+    // MLFQ level
+    pub level: usize,
+    // Timer ticks used at this MLFQ level
+    pub used: usize,
+}
+
+impl Default for SchedInfo {
+    fn default() -> Self {
+        Self {
+            nice: 0,
+            affinity: ALL_CPUS,
+            ticks: 0,
+            level: 0,
+            used: 0,
+        }
+    }
+}
+
+impl SchedInfo {
+    pub fn can_run_on(&self, cpu_id: usize) -> bool {
+        cpu_id < u64::BITS as usize && self.affinity & (1 << cpu_id) != 0
+    }
+}
+
+// Something a policy can schedule (a process in the kernel)
+pub trait Task {
+    fn sched_info(&self) -> &SchedInfo;
+    fn sched_info_mut(&mut self) -> &mut SchedInfo;
+}
+
+// A run queue ordered by a policy, the kernel has one per hart
+// The tasks are owned by the queue while they are runnable
+pub trait SchedPolicy<T: Task> {
+    // `time_slice` is in timer ticks
+    fn new(time_slice: usize) -> Self
+    where
+        Self: Sized;
+
+    // Add a runnable task (new, preempted, yielding or woken up)
+    fn enqueue(&mut self, task: T);
+
+    // Remove the task to run next, `now` is the number of timer ticks since boot
+    fn pick_next(&mut self, now: u64) -> Option<T>;
+
+    // Remove a task to run it on the hart `cpu_id`, its affinity must allow it
+    fn steal(&mut self, cpu_id: usize) -> Option<T>;
+
+    // Remove the first task for which `f` returns true
+    fn remove(&mut self, f: impl Fn(&T) -> bool) -> Option<T>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Called on every timer tick for the running task, returns true if it must be preempted
+    fn on_tick(&mut self, task: &mut T) -> bool;
+
+    // The running task is going to sleep
+    fn on_block(&mut self, task: &mut T);
+
+    // The task has been woken up, called before `enqueue`
+    fn on_wake(&mut self, task: &mut T);
+
+    // The nice value of the task has changed
+    fn on_nice(&mut self, _task: &mut T) {}
+}
+
+// A task known by its number for the tests of the policies
+#[cfg(test)]
+#[derive(Debug)]
+struct TestTask {
+    id: usize,
+    sched: SchedInfo,
+}
+
+#[cfg(test)]
+impl TestTask {
+    fn new(id: usize, nice: i32) -> Self {
+        Self {
+            id,
+            sched: SchedInfo {
+                nice,
+                ..SchedInfo::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl Task for TestTask {
+    fn sched_info(&self) -> &SchedInfo {
+        &self.sched
+    }
+
+    fn sched_info_mut(&mut self) -> &mut SchedInfo {
+        &mut self.sched
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{SchedInfo, SchedPolicy, Task, MAX_NICE, MIN_NICE};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// Level 0 is run first
+const NUM_LEVELS: usize = 5;
+// Nice values are split in NICE_LEVELS levels, the lower levels are only reached by using whole time slices
+const NICE_LEVELS: usize = 4;
+// In timer ticks
+const BOOST_PERIOD: u64 = 100;
+
+// Multilevel feedback queue: a task starts in the level given by its nice value,
+// it goes down one level each time it has used the whole time slice of its level
+// and up one level (but not above its nice level) when it is woken up after blocking
+// All the tasks of the queue go back to their nice level every BOOST_PERIOD ticks so none of them starves
+pub struct Mlfq<T> {
+    levels: [VecDeque<T>; NUM_LEVELS],
+    time_slice: usize,
+    // In timer ticks since boot
+    next_boost: u64,
+}
+
+// The highest level a task can be in
+fn nice_level(nice: i32) -> usize {
+    (nice - MIN_NICE) as usize * NICE_LEVELS / (MAX_NICE - MIN_NICE + 1) as usize
+}
+
+impl<T: Task> Mlfq<T> {
+    // Each level below the nice level has a time slice twice as long as the previous one
+    fn level_time_slice(&self, info: &SchedInfo) -> usize {
+        // The nice value may have been raised since the task was enqueued (see on_nice)
+        self.time_slice << info.level.saturating_sub(nice_level(info.nice))
+    }
+
+    fn boost(&mut self) {
+        let tasks: Vec<T> = self.levels.iter_mut().flat_map(|level| level.drain(..)).collect();
+        for mut task in tasks {
+            reset_level(task.sched_info_mut());
+            self.enqueue(task);
+        }
+    }
+}
+
+fn reset_level(info: &mut SchedInfo) {
+    info.level = nice_level(info.nice);
+    info.used = 0;
+}
+
+impl<T: Task> SchedPolicy<T> for Mlfq<T> {
+    fn new(time_slice: usize) -> Self {
+        Self {
+            levels: Default::default(),
+            time_slice,
+            next_boost: 0,
+        }
+    }
+
+    // A new task starts at its nice level
+    fn enqueue(&mut self, mut task: T) {
+        let info = task.sched_info_mut();
+        info.level = core::cmp::max(info.level, nice_level(info.nice));
+        let level = info.level;
+        self.levels[level].push_back(task);
+    }
+
+    // The first task of the highest non empty level
+    fn pick_next(&mut self, now: u64) -> Option<T> {
+        if now >= self.next_boost {
+            self.boost();
+            self.next_boost = now + BOOST_PERIOD;
+        }
+        self.levels.iter_mut().find_map(VecDeque::pop_front)
+    }
+
+    // The last task of the lowest level
+    fn steal(&mut self, cpu_id: usize) -> Option<T> {
+        self.levels.iter_mut().rev().find_map(|level| {
+            let i = level.iter().rposition(|task| task.sched_info().can_run_on(cpu_id))?;
+            level.remove(i)
+        })
+    }
+
+    fn remove(&mut self, f: impl Fn(&T) -> bool) -> Option<T> {
+        self.levels.iter_mut().find_map(|level| {
+            let i = level.iter().position(&f)?;
+            level.remove(i)
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    // The task is demoted once it has used the whole time slice of its level
+    fn on_tick(&mut self, task: &mut T) -> bool {
+        let level_time_slice = self.level_time_slice(task.sched_info());
+        let info = task.sched_info_mut();
+        info.ticks += 1;
+        info.used += 1;
+        if info.used < level_time_slice {
+            return false;
+        }
+        info.level = core::cmp::min(info.level + 1, NUM_LEVELS - 1);
+        info.used = 0;
+        true
+    }
+
+    fn on_block(&mut self, _task: &mut T) {}
+
+    // Tasks which block before the end of their time slice are interactive, they go up one level
+    fn on_wake(&mut self, task: &mut T) {
+        let info = task.sched_info_mut();
+        info.level = core::cmp::max(info.level.saturating_sub(1), nice_level(info.nice));
+        info.used = 0;
+    }
+
+    fn on_nice(&mut self, task: &mut T) {
+        reset_level(task.sched_info_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestTask;
+
+    const TIME_SLICE: usize = 2;
+
+    // Run the task until it is preempted, returns the number of ticks
+    fn run_slice(mlfq: &mut Mlfq<TestTask>, task: &mut TestTask) -> usize {
+        let mut ticks = 1;
+        while !mlfq.on_tick(task) {
+            ticks += 1;
+        }
+        ticks
+    }
+
+    #[test]
+    fn lower_nice_runs_first() {
+        let mut mlfq = Mlfq::new(TIME_SLICE);
+        mlfq.enqueue(TestTask::new(0, 10));
+        mlfq.enqueue(TestTask::new(1, 0));
+        mlfq.enqueue(TestTask::new(2, MIN_NICE));
+        let ids: Vec<usize> = core::iter::from_fn(|| mlfq.pick_next(0)).map(|task| task.id).collect();
+        assert_eq!(ids, [2, 1, 0]);
+    }
+
+    #[test]
+    fn demoted_after_whole_slice() {
+        let mut mlfq = Mlfq::new(TIME_SLICE);
+        mlfq.enqueue(TestTask::new(0, 0));
+        mlfq.enqueue(TestTask::new(1, 0));
+        let mut hog = mlfq.pick_next(0).unwrap();
+        assert_eq!(hog.id, 0);
+        assert_eq!(run_slice(&mut mlfq, &mut hog), TIME_SLICE);
+        assert_eq!(hog.sched.level, nice_level(0) + 1);
+        mlfq.enqueue(hog);
+
+        // The other task runs first, the demoted one then gets a slice twice as long
+        assert_eq!(mlfq.pick_next(1).unwrap().id, 1);
+        let mut hog = mlfq.pick_next(2).unwrap();
+        assert_eq!(run_slice(&mut mlfq, &mut hog), 2 * TIME_SLICE);
+        assert_eq!(hog.sched.level, nice_level(0) + 2);
+    }
+
+    #[test]
+    fn woken_up_goes_up_to_nice_level() {
+        let mut mlfq: Mlfq<TestTask> = Mlfq::new(TIME_SLICE);
+        let mut task = TestTask::new(0, 0);
+        task.sched.level = nice_level(0) + 1;
+        mlfq.on_wake(&mut task);
+        assert_eq!(task.sched.level, nice_level(0));
+        mlfq.on_wake(&mut task);
+        assert_eq!(task.sched.level, nice_level(0));
+    }
+
+    #[test]
+    fn boost_resets_levels() {
+        let mut mlfq = Mlfq::new(TIME_SLICE);
+        mlfq.enqueue(TestTask::new(0, 0));
+        let mut hog = mlfq.pick_next(0).unwrap();
+        run_slice(&mut mlfq, &mut hog);
+        mlfq.enqueue(hog);
+        // No boost before BOOST_PERIOD
+        let mut hog = mlfq.pick_next(BOOST_PERIOD - 1).unwrap();
+        assert_eq!(hog.sched.level, nice_level(0) + 1);
+        run_slice(&mut mlfq, &mut hog);
+        mlfq.enqueue(hog);
+        let hog = mlfq.pick_next(BOOST_PERIOD).unwrap();
+        assert_eq!(hog.sched.level, nice_level(0));
+        assert_eq!(hog.sched.used, 0);
+    }
+
+    #[test]
+    fn nice_raised_while_running() {
+        let mut mlfq: Mlfq<TestTask> = Mlfq::new(TIME_SLICE);
+        let mut task = TestTask::new(0, 0);
+        task.sched.level = nice_level(0);
+        // The level is below the new nice level until on_nice is called
+        task.sched.nice = MAX_NICE;
+        assert_eq!(run_slice(&mut mlfq, &mut task), TIME_SLICE);
+    }
+}
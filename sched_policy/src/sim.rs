@@ -0,0 +1,128 @@
+// Deterministic simulation of a policy on one hart with synthetic tasks, one step per timer tick
+// Used to compare the policies on the host (see examples/simulate.rs)
+
+use crate::{SchedInfo, SchedPolicy, Task};
+use alloc::vec;
+use alloc::vec::Vec;
+
+// A synthetic task: it runs `burst` ticks then sleeps `block` ticks until it has run `work` ticks
+// A task with a `burst` of 0 never sleeps
+#[derive(Debug, Clone)]
+pub struct TaskSpec {
+    pub name: &'static str,
+    pub nice: i32,
+    // Tick at which the task becomes runnable
+    pub arrival: u64,
+    pub work: u64,
+    pub burst: u64,
+    pub block: u64,
+}
+
+// All times are in ticks
+#[derive(Debug, Clone)]
+pub struct TaskStats {
+    pub name: &'static str,
+    // From the arrival to the first run
+    pub response: Option<u64>,
+    // From the arrival to the end
+    pub turnaround: Option<u64>,
+    // Runnable but not running
+    pub wait: u64,
+    // Number of times the task has been picked
+    pub runs: u64,
+}
+
+pub struct SimTask {
+    id: usize,
+    sched: SchedInfo,
+    done: u64,
+    burst_left: u64,
+}
+
+impl Task for SimTask {
+    fn sched_info(&self) -> &SchedInfo {
+        &self.sched
+    }
+
+    fn sched_info_mut(&mut self) -> &mut SchedInfo {
+        &mut self.sched
+    }
+}
+
+// Run the workload until every task has finished or `max_ticks` have passed
+pub fn simulate<P: SchedPolicy<SimTask>>(time_slice: usize, workload: &[TaskSpec], max_ticks: u64) -> Vec<TaskStats> {
+    let mut policy = P::new(time_slice);
+    let mut stats: Vec<TaskStats> = workload
+        .iter()
+        .map(|spec| TaskStats {
+            name: spec.name,
+            response: None,
+            turnaround: None,
+            wait: 0,
+            runs: 0,
+        })
+        .collect();
+    // Ticks spent sleeping by each task
+    let mut blocked = vec![0; workload.len()];
+    // (tick at which it is woken up, task)
+    let mut sleeping: Vec<(u64, SimTask)> = Vec::new();
+    let mut running: Option<SimTask> = None;
+    let burst = |spec: &TaskSpec| if spec.burst == 0 { spec.work } else { spec.burst };
+
+    for now in 0..max_ticks {
+        for (id, spec) in workload.iter().enumerate().filter(|(_, spec)| spec.arrival == now) {
+            policy.enqueue(SimTask {
+                id,
+                sched: SchedInfo {
+                    nice: spec.nice,
+                    ..SchedInfo::default()
+                },
+                done: 0,
+                burst_left: burst(spec),
+            });
+        }
+        while let Some(i) = sleeping.iter().position(|(wake_at, _)| *wake_at == now) {
+            let (_, mut task) = sleeping.swap_remove(i);
+            policy.on_wake(&mut task);
+            policy.enqueue(task);
+        }
+
+        if running.is_none() {
+            running = policy.pick_next(now).map(|mut task| {
+                task.sched.ticks = 0;
+                let stats = &mut stats[task.id];
+                stats.runs += 1;
+                stats.response.get_or_insert(now - workload[task.id].arrival);
+                task
+            });
+        }
+        let Some(mut task) = running.take() else {
+            if sleeping.is_empty() && workload.iter().all(|spec| spec.arrival < now) {
+                break;
+            }
+            continue;
+        };
+
+        // Run the task during this tick
+        let spec = &workload[task.id];
+        task.done += 1;
+        task.burst_left -= 1;
+        let preempt = policy.on_tick(&mut task);
+        if task.done == spec.work {
+            let turnaround = now + 1 - spec.arrival;
+            let stats = &mut stats[task.id];
+            stats.turnaround = Some(turnaround);
+            stats.wait = turnaround - spec.work - blocked[task.id];
+        } else if task.burst_left == 0 {
+            policy.on_block(&mut task);
+            task.burst_left = burst(spec);
+            blocked[task.id] += spec.block;
+            sleeping.push((now + 1 + spec.block, task));
+        } else if preempt {
+            policy.enqueue(task);
+        } else {
+            running = Some(task);
+        }
+    }
+    stats
+}
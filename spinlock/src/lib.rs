@@ -0,0 +1,225 @@
+#![no_std]
+
+// A spinlock which disables the interrupts while it is held
+// Otherwise an interrupt handler (or a process preempted by the timer) could wait forever for a lock
+// held by the code it has interrupted on the same hart
+// In debug builds the order in which the locks are taken is checked (see lockdep)
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(debug_assertions)]
+mod lockdep;
+
+// Must be at least the number of harts started by the kernel (MAX_CPUS)
+pub const MAX_HARTS: usize = 8;
+
+// Only used by its hart with the interrupts disabled
+struct HartState {
+    // Number of push_off not yet matched by a pop_off
+    push_count: AtomicUsize,
+    // Were the interrupts enabled before the first push_off
+    interrupt_base: AtomicBool,
+}
+
+static HART_STATES: [HartState; MAX_HARTS] = [const {
+    HartState {
+        push_count: AtomicUsize::new(0),
+        interrupt_base: AtomicBool::new(false),
+    }
+}; MAX_HARTS];
+
+// The kernel keeps the hart id in tp (set in entry.S)
+#[cfg(target_arch = "riscv64")]
+pub fn hart_id() -> usize {
+    let hart_id: usize;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) hart_id);
+    }
+    hart_id
+}
+
+// The tests run on the host (see lockdep), they do not use the hart states
+#[cfg(not(target_arch = "riscv64"))]
+pub fn hart_id() -> usize {
+    0
+}
+
+fn hart_state() -> &'static HartState {
+    &HART_STATES[hart_id()]
+}
+
+pub fn interrupts_enabled() -> bool {
+    riscv::register::sstatus::read().sie()
+}
+
+// Disable the interrupts, they are enabled again by the matching pop_off if they were enabled
+// Nested calls need the same number of pop_off
+pub fn push_off() {
+    let enabled = interrupts_enabled();
+    unsafe {
+        riscv::register::sstatus::clear_sie();
+    }
+    let state = hart_state();
+    if state.push_count.load(Ordering::Relaxed) == 0 {
+        state.interrupt_base.store(enabled, Ordering::Relaxed);
+    }
+    state.push_count.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn pop_off() {
+    assert!(!interrupts_enabled(), "pop_off with the interrupts enabled");
+    let state = hart_state();
+    let count = state.push_count.load(Ordering::Relaxed);
+    assert!(count > 0, "pop_off without push_off");
+    state.push_count.store(count - 1, Ordering::Relaxed);
+    if count == 1 && state.interrupt_base.load(Ordering::Relaxed) {
+        unsafe {
+            riscv::register::sstatus::set_sie();
+        }
+    }
+}
+
+pub fn push_count() -> usize {
+    hart_state().push_count.load(Ordering::Relaxed)
+}
+
+// The state to restore by the last pop_off belongs to the code running and not to the hart,
+// it must be saved and restored around a context switch
+pub fn interrupt_base() -> bool {
+    hart_state().interrupt_base.load(Ordering::Relaxed)
+}
+
+/// # Safety
+/// Only called by the context switch on the current hart, with the interrupts disabled and the value
+/// that `interrupt_base` returned before switching, otherwise the last pop_off could enable the interrupts
+/// while a lock is held
+pub unsafe fn set_interrupt_base(enabled: bool) {
+    hart_state().interrupt_base.store(enabled, Ordering::Relaxed);
+}
+
+// The locks with the same name are in the same class for lockdep
+struct LockClass {
+    name: &'static str,
+    // The lockdep class + 1, 0 until the lock is taken for the first time
+    #[cfg(debug_assertions)]
+    id: AtomicUsize,
+}
+
+impl LockClass {
+    #[cfg(debug_assertions)]
+    fn id(&self) -> usize {
+        match self.id.load(Ordering::Relaxed) {
+            0 => {
+                let id = lockdep::register(self.name);
+                self.id.store(id + 1, Ordering::Relaxed);
+                id
+            }
+            id => id - 1,
+        }
+    }
+}
+
+pub struct SpinLock<T: ?Sized> {
+    class: LockClass,
+    lock: spin::Mutex<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            class: LockClass {
+                name,
+                #[cfg(debug_assertions)]
+                id: AtomicUsize::new(0),
+            },
+            lock: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        push_off();
+        #[cfg(debug_assertions)]
+        lockdep::acquire(self.class.id(), self.addr(), false);
+        SpinLockGuard {
+            lock: self,
+            guard: Some(self.lock.lock()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        push_off();
+        match self.lock.try_lock() {
+            Some(guard) => {
+                #[cfg(debug_assertions)]
+                lockdep::acquire(self.class.id(), self.addr(), true);
+                Some(SpinLockGuard {
+                    lock: self,
+                    guard: Some(guard),
+                })
+            }
+            None => {
+                pop_off();
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.class.name
+    }
+
+    // Release a lock whose guard has been forgotten
+    /// # Safety
+    /// Only for a guard given to `mem::forget` by the current holder, on the hart which has taken the lock
+    /// (after a context switch for instance), the lock must not be used through another guard
+    pub unsafe fn force_unlock(&self) {
+        self.lock.force_unlock();
+        self.release();
+    }
+
+    #[cfg(debug_assertions)]
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    // Once the lock has been released
+    fn release(&self) {
+        #[cfg(debug_assertions)]
+        lockdep::release(self.addr());
+        pop_off();
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    // Only None while dropping, the lock must be released before the pop_off
+    guard: Option<spin::MutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.lock.release();
+    }
+}
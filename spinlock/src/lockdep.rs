@@ -0,0 +1,282 @@
+// Lock dependency validator, only in debug builds
+// Every lock has a class (its name, the locks of a per hart array or of every wait queue share one),
+// taking a lock of class B while holding a lock of class A records the dependency A -> B with the chain of
+// locks held at that time. Taking a lock which would close a cycle (B held while taking A somewhere else)
+// could deadlock even if it never did, so it panics with the chain recorded and the current one.
+
+use crate::{hart_id, MAX_HARTS};
+use core::fmt;
+use spin::Mutex;
+
+const MAX_CLASSES: usize = 32;
+// Locks held at the same time by a hart
+const MAX_HELD: usize = 8;
+
+// Classes of the locks taken one after the other
+#[derive(Clone, Copy)]
+struct Chain {
+    classes: [u8; MAX_HELD + 1],
+    len: usize,
+}
+
+struct Graph {
+    names: [&'static str; MAX_CLASSES],
+    class_count: usize,
+    // edges[a][b] is the chain which has first taken b while holding a
+    edges: [[Option<Chain>; MAX_CLASSES]; MAX_CLASSES],
+}
+
+// The locks of lockdep are not validated, the interrupts are already disabled when they are taken
+static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+    names: [""; MAX_CLASSES],
+    class_count: 0,
+    edges: [[None; MAX_CLASSES]; MAX_CLASSES],
+});
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    class: usize,
+    addr: usize,
+}
+
+struct Held {
+    locks: [HeldLock; MAX_HELD],
+    len: usize,
+}
+
+// Locks held by each hart, in the order they have been taken
+static HELD: [Mutex<Held>; MAX_HARTS] = [const {
+    Mutex::new(Held {
+        locks: [HeldLock { class: 0, addr: 0 }; MAX_HELD],
+        len: 0,
+    })
+}; MAX_HARTS];
+
+// Returns the class named `name`
+pub(crate) fn register(name: &'static str) -> usize {
+    let mut graph = GRAPH.lock();
+    if let Some(class) = graph.names[..graph.class_count].iter().position(|&n| n == name) {
+        return class;
+    }
+    let class = graph.class_count;
+    assert!(class < MAX_CLASSES, "Too many lock classes");
+    graph.names[class] = name;
+    graph.class_count += 1;
+    class
+}
+
+// Called before spinning on the lock at `addr`, `try_lock` does not wait so it cannot deadlock
+// The panics leave HELD locked, the panic handler prints with SBI without taking a SpinLock
+pub(crate) fn acquire(class: usize, addr: usize, try_lock: bool) {
+    acquire_held(&mut HELD[hart_id()].lock(), class, addr, try_lock);
+}
+
+// The locks may be released in any order
+pub(crate) fn release(addr: usize) {
+    release_held(&mut HELD[hart_id()].lock(), addr);
+}
+
+fn acquire_held(held: &mut Held, class: usize, addr: usize, try_lock: bool) {
+    let chain = Chain::from_held(held, class);
+    if !try_lock {
+        if held.locks[..held.len].iter().any(|lock| lock.addr == addr) {
+            let names = GRAPH.lock().names;
+            panic!("Lockdep: self deadlock\n  current chain: {}", chain.display(&names));
+        }
+        check_order(held, class, chain);
+    }
+    assert!(held.len < MAX_HELD, "Lockdep: too many locks held");
+    let len = held.len;
+    held.locks[len] = HeldLock { class, addr };
+    held.len += 1;
+}
+
+fn release_held(held: &mut Held, addr: usize) {
+    let len = held.len;
+    let i = held.locks[..len]
+        .iter()
+        .rposition(|lock| lock.addr == addr)
+        .expect("Lockdep: releasing a lock which is not held");
+    held.locks.copy_within(i + 1..len, i);
+    held.len -= 1;
+}
+
+fn check_order(held: &Held, class: usize, chain: Chain) {
+    let mut graph = GRAPH.lock();
+    for lock in &held.locks[..held.len] {
+        // Locks of the same class (the run queues of two harts...) are not ordered
+        if lock.class == class || graph.edges[lock.class][class].is_some() {
+            continue;
+        }
+        if let Some(previous) = graph.find_path(class, lock.class) {
+            let names = graph.names;
+            drop(graph);
+            panic!(
+                "Lockdep: lock order inversion, {} taken while holding {}\n  current chain: {}\n  previous chain: {}",
+                names[class],
+                names[lock.class],
+                chain.display(&names),
+                previous.display(&names)
+            );
+        }
+        graph.edges[lock.class][class] = Some(chain);
+    }
+}
+
+impl Graph {
+    // If `to` has been taken while (indirectly) holding `from`, returns the chain of the last dependency
+    fn find_path(&self, from: usize, to: usize) -> Option<Chain> {
+        let mut visited = [false; MAX_CLASSES];
+        let mut stack = [0; MAX_CLASSES];
+        let mut stack_len = 1;
+        stack[0] = from;
+        visited[from] = true;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let class = stack[stack_len];
+            for (next, edge) in self.edges[class][..self.class_count].iter().enumerate() {
+                let Some(chain) = *edge else {
+                    continue;
+                };
+                if next == to {
+                    return Some(chain);
+                }
+                if !visited[next] {
+                    visited[next] = true;
+                    stack[stack_len] = next;
+                    stack_len += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Chain {
+    fn from_held(held: &Held, class: usize) -> Self {
+        let mut classes = [0; MAX_HELD + 1];
+        for (i, lock) in held.locks[..held.len].iter().enumerate() {
+            classes[i] = lock.class as u8;
+        }
+        classes[held.len] = class as u8;
+        Self {
+            classes,
+            len: held.len + 1,
+        }
+    }
+
+    fn display<'a>(&'a self, names: &'a [&'static str; MAX_CLASSES]) -> impl fmt::Display + 'a {
+        ChainDisplay { chain: self, names }
+    }
+}
+
+struct ChainDisplay<'a> {
+    chain: &'a Chain,
+    names: &'a [&'static str; MAX_CLASSES],
+}
+
+impl fmt::Display for ChainDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &class) in self.chain.classes[..self.chain.len].iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", self.names[class as usize])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The graph is shared by the tests, each one uses its own classes
+    fn new_held() -> Held {
+        Held {
+            locks: [HeldLock { class: 0, addr: 0 }; MAX_HELD],
+            len: 0,
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion, inversion_a taken while holding inversion_b")]
+    fn order_inversion() {
+        let (a, b) = (register("inversion_a"), register("inversion_b"));
+        let mut held = new_held();
+        acquire_held(&mut held, a, 1, false);
+        acquire_held(&mut held, b, 2, false);
+        release_held(&mut held, 2);
+        release_held(&mut held, 1);
+
+        acquire_held(&mut held, b, 2, false);
+        acquire_held(&mut held, a, 1, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion, indirect_a taken while holding indirect_c")]
+    fn indirect_inversion() {
+        let (a, b, c) = (register("indirect_a"), register("indirect_b"), register("indirect_c"));
+        let mut held = new_held();
+        acquire_held(&mut held, a, 1, false);
+        acquire_held(&mut held, b, 2, false);
+        release_held(&mut held, 1);
+        acquire_held(&mut held, c, 3, false);
+        release_held(&mut held, 3);
+        release_held(&mut held, 2);
+
+        acquire_held(&mut held, c, 3, false);
+        acquire_held(&mut held, a, 1, false);
+    }
+
+    #[test]
+    fn same_order_and_try_lock() {
+        let (a, b) = (register("ordered_a"), register("ordered_b"));
+        let mut held = new_held();
+        for _ in 0..2 {
+            acquire_held(&mut held, a, 1, false);
+            acquire_held(&mut held, b, 2, false);
+            release_held(&mut held, 1);
+            release_held(&mut held, 2);
+        }
+        // A try_lock cannot deadlock, the other order is allowed
+        acquire_held(&mut held, b, 2, false);
+        acquire_held(&mut held, a, 1, true);
+        assert_eq!(held.len, 2);
+    }
+
+    #[test]
+    fn same_class_not_ordered() {
+        let class = register("same_class");
+        assert_eq!(register("same_class"), class);
+        let mut held = new_held();
+        acquire_held(&mut held, class, 1, false);
+        acquire_held(&mut held, class, 2, false);
+        release_held(&mut held, 1);
+        release_held(&mut held, 2);
+        acquire_held(&mut held, class, 2, false);
+        acquire_held(&mut held, class, 1, false);
+        assert_eq!(held.len, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "self deadlock")]
+    fn same_lock_twice() {
+        let class = register("same_lock");
+        let mut held = new_held();
+        acquire_held(&mut held, class, 1, false);
+        acquire_held(&mut held, class, 1, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many locks held")]
+    fn max_held() {
+        let class = register("max_held");
+        let mut held = new_held();
+        for addr in 0..MAX_HELD {
+            acquire_held(&mut held, class, addr, false);
+        }
+        assert_eq!(held.len, MAX_HELD);
+        acquire_held(&mut held, class, MAX_HELD, false);
+    }
+}
@@ -1,9 +1,13 @@
 use crate::proc::{Proc, ProcContext};
+use crate::scheduler::{base_time_slice, RunQueue};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 use fdt::Fdt;
-use spin::{Mutex, MutexGuard, Once};
+use sched_policy::SchedPolicy;
+use spin::Once;
+use spinlock::{SpinLock, SpinLockGuard};
 
 // Here we are using the register tp (Thread Pointer) just as a storage variable (because there are currently no thread)
 pub fn get_cpuid() -> usize {
@@ -27,8 +31,6 @@ pub fn write_tp(tp: usize) {
 pub(crate) struct Cpu {
     pub proc: Option<Box<Proc>>,
     pub scheduler_context: ProcContext,
-    // pub interrupt_base: Mutex<bool>,
-    // pub push_count: Mutex<u32>,
 }
 
 impl Cpu {
@@ -40,20 +42,68 @@ impl Cpu {
                 sp: 0,
                 s: [0; 12],
             },
-            // interrupt_base: Mutex::new(false),
-            // push_count: Mutex::new(0),
         }
     }
 }
 
-static CPUS: Once<Vec<Mutex<Cpu>>> = Once::new();
+// Everything a hart has, the cpu is only used by its hart but the run queue is also used by the other harts
+struct PerCpu {
+    cpu: SpinLock<Cpu>,
+    // The processes runnable on this hart
+    run_queue: SpinLock<RunQueue>,
+    // Set when the hart has nothing to run and waits for an interrupt
+    idle: AtomicBool,
+}
+
+static CPUS: Once<Vec<PerCpu>> = Once::new();
 
 pub fn init_cpus(fdt: &Fdt) {
-    CPUS.call_once(|| fdt.cpus().map(|_| Mutex::new(Cpu::new())).collect());
+    CPUS.call_once(|| {
+        fdt.cpus()
+            .map(|_| PerCpu {
+                cpu: SpinLock::new("cpu", Cpu::new()),
+                run_queue: SpinLock::new("run_queue", RunQueue::new(base_time_slice())),
+                idle: AtomicBool::new(false),
+            })
+            .collect()
+    });
 }
 
-pub(crate) fn get_cpu() -> MutexGuard<'static, Cpu> {
+pub(crate) fn get_cpu() -> SpinLockGuard<'static, Cpu> {
     let cpu_id = get_cpuid();
     let cpus = CPUS.get().unwrap();
-    cpus.get(cpu_id).unwrap().lock()
+    cpus.get(cpu_id).unwrap().cpu.lock()
+}
+
+// Used in the timer interrupt which may happen before the cpus are initialized
+pub(crate) fn try_get_cpu() -> Option<SpinLockGuard<'static, Cpu>> {
+    let cpu_id = get_cpuid();
+    let cpus = CPUS.get()?;
+    cpus.get(cpu_id).unwrap().cpu.try_lock()
+}
+
+pub(crate) fn cpu_count() -> usize {
+    CPUS.get().unwrap().len()
+}
+
+// Bit n is set for the hart n
+pub(crate) fn online_cpus() -> u64 {
+    (1 << cpu_count()) - 1
+}
+
+pub(crate) fn run_queue(cpu_id: usize) -> &'static SpinLock<RunQueue> {
+    &CPUS.get().unwrap()[cpu_id].run_queue
+}
+
+pub(crate) fn is_idle(cpu_id: usize) -> bool {
+    CPUS.get().unwrap()[cpu_id].idle.load(Ordering::SeqCst)
+}
+
+pub(crate) fn set_idle(idle: bool) {
+    CPUS.get().unwrap()[get_cpuid()].idle.store(idle, Ordering::SeqCst);
+}
+
+// Wake up a hart waiting in wfi with a software interrupt
+pub(crate) fn send_wakeup_ipi(cpu_id: usize) {
+    sbi::ipi::send_ipi(sbi::HartMask::from(cpu_id)).unwrap();
 }
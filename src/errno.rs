@@ -0,0 +1,32 @@
+// Error numbers returned to user space (same values as Linux)
+// The variants keep the Linux errno names
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(isize)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENODEV = 19,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+}
+
+impl Errno {
+    // The value put in a0 when a syscall fails
+    pub fn as_ret(self) -> u64 {
+        (-(self as isize)) as u64
+    }
+}
@@ -0,0 +1,178 @@
+use crate::errno::Errno;
+use crate::cpu::get_cpu;
+use crate::trapframe::TrapFrame;
+use crate::programs::find_program;
+use crate::swap::{free_swapped_pages, reclaim};
+use crate::vm::{new_user_page_table, USER_STACK_TOP};
+use crate::vma::{MemoryMap, Vma, VmaKind};
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::mem::size_of;
+use page_alloc::{page_round_up, PAGE_ALLOCATOR, PAGE_SIZE};
+use page_table::entry::addr::{PhysicalAddr, VirtualAddr};
+use page_table::entry::perm::PTEPermission;
+use page_table::PageTable;
+
+// Auxiliary vector types (see the System V ABI)
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+
+// Replace the address space of the current process with the program at `path`
+// The old address space is only freed once the new one is fully built, so on error the process is untouched
+// The new one is built without locking the cpu as it may wait for the swap
+// Returns argc which is also the value of a0 when the process returns to user mode
+pub(crate) fn exec(path: &str, argv: &[&str], envp: &[&str]) -> Result<usize, Errno> {
+    let image = find_program(path).ok_or(Errno::ENOENT)?;
+    reclaim();
+
+    // The trap frame lives as long as the process
+    let trap_frame: *const TrapFrame = get_cpu().proc.as_ref().unwrap().trap_frame.as_ref();
+    let mut page_table = new_user_page_table(unsafe { &*trap_frame });
+    let mut memory_map = MemoryMap::new();
+    let sp = match build_address_space(&mut page_table, &mut memory_map, image, argv, envp) {
+        Ok(sp) => sp,
+        Err(e) => {
+            page_table.free_user_pages();
+            return Err(e);
+        }
+    };
+
+    let mut cpu = get_cpu();
+    let proc = cpu.proc.as_mut().unwrap();
+    let trap_frame = proc.trap_frame.as_mut();
+    trap_frame.epc = 0; // Entry point of the flat binary
+    trap_frame.sp = sp;
+    trap_frame.a0 = argv.len() as u64;
+    trap_frame.a1 = sp + size_of::<u64>() as u64; // argv is just after argc
+
+    proc.name = String::from(path.rsplit('/').next().unwrap_or(path));
+
+    proc.memory_size = memory_map.heap().start;
+    let mut memory = proc.memory.lock();
+    let memory = &mut *memory;
+    memory.memory_map.unmap_all_shm(&mut memory.page_table);
+    let mut old_page_table = core::mem::replace(&mut memory.page_table, page_table);
+    free_swapped_pages(&mut old_page_table);
+    old_page_table.free_user_pages();
+    memory.memory_map = memory_map;
+
+    Ok(argv.len())
+}
+
+// Returns the user stack pointer
+fn build_address_space(
+    page_table: &mut Box<PageTable>,
+    memory_map: &mut MemoryMap,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<u64, Errno> {
+    // Load the code
+    let image_size = page_round_up(image.len() as u64);
+    memory_map.insert(Vma {
+        start: 0,
+        end: image_size,
+        perm: PTEPermission::read() | PTEPermission::execute(),
+        kind: VmaKind::Code,
+    });
+    for (i, chunk) in image.chunks(PAGE_SIZE).enumerate() {
+        let page = alloc_user_page(
+            page_table,
+            VirtualAddr::new((i * PAGE_SIZE) as u64),
+            PTEPermission::read() | PTEPermission::execute() | PTEPermission::user(),
+        )?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(chunk.as_ptr(), page, chunk.len());
+        }
+    }
+
+    memory_map.insert_heap(image_size);
+
+    // The arguments are in the last page of the stack, the other pages are allocated when touched
+    memory_map.insert_stack();
+    let args_page_addr = USER_STACK_TOP - PAGE_SIZE as u64;
+    let args_page = alloc_user_page(
+        page_table,
+        VirtualAddr::new(args_page_addr),
+        PTEPermission::read() | PTEPermission::write() | PTEPermission::user(),
+    )?;
+
+    push_arguments(args_page, args_page_addr, argv, envp)
+}
+
+fn alloc_user_page(
+    page_table: &mut PageTable,
+    va: VirtualAddr,
+    perm: PTEPermission,
+) -> Result<*mut u8, Errno> {
+    let page = PAGE_ALLOCATOR.kalloc().map_err(|_| Errno::ENOMEM)?;
+    // Pages from the PAGE_ALLOCATOR are identity mapped in the kernel
+    let pa = PhysicalAddr::new(usize::from(page.addr()) as u64);
+    if page_table.try_map_pages(va, pa, PAGE_SIZE, perm, 0).is_err() {
+        PAGE_ALLOCATOR.kfree(page);
+        return Err(Errno::ENOMEM);
+    }
+    Ok(page.as_ptr())
+}
+
+// Layout of the stack (from the top):
+// - argv and envp strings
+// - auxv (terminated by AT_NULL)
+// - envp pointers (terminated by NULL)
+// - argv pointers (terminated by NULL)
+// - argc <- sp (aligned on 16 bytes)
+fn push_arguments(
+    stack_page: *mut u8,
+    stack_bottom: u64,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<u64, Errno> {
+    let stack_top = stack_bottom + PAGE_SIZE as u64;
+    let mut sp = stack_top;
+
+    let mut push_str = |s: &str| -> Result<u64, Errno> {
+        let len = s.len() as u64 + 1; // With the NUL byte
+        if sp - stack_bottom < len {
+            return Err(Errno::E2BIG);
+        }
+        sp -= len;
+        unsafe {
+            let dst = stack_page.add((sp - stack_bottom) as usize);
+            core::ptr::copy_nonoverlapping(s.as_ptr(), dst, s.len());
+            dst.add(s.len()).write(0);
+        }
+        Ok(sp)
+    };
+    let envp_ptrs = envp
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<alloc::vec::Vec<u64>, Errno>>()?;
+    let argv_ptrs = argv
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<alloc::vec::Vec<u64>, Errno>>()?;
+
+    let auxv = [AT_PAGESZ, PAGE_SIZE as u64, AT_NULL, 0];
+    let words = core::iter::once(argv.len() as u64)
+        .chain(argv_ptrs)
+        .chain(core::iter::once(0))
+        .chain(envp_ptrs)
+        .chain(core::iter::once(0))
+        .chain(auxv);
+    let word_count = 1 + argv.len() + 1 + envp.len() + 1 + auxv.len();
+
+    let size = (word_count * size_of::<u64>()) as u64;
+    if sp - stack_bottom < size + 16 {
+        return Err(Errno::E2BIG);
+    }
+    sp = (sp - size) & !15;
+
+    for (i, word) in words.enumerate() {
+        unsafe {
+            let dst = stack_page.add((sp - stack_bottom) as usize + i * size_of::<u64>());
+            dst.cast::<u64>().write(word);
+        }
+    }
+
+    Ok(sp)
+}
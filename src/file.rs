@@ -0,0 +1,154 @@
+// Open files of a process, indexed by file descriptor
+// There is no file system yet, the files are the console (see tty.rs) and the shared memory objects (see shm.rs)
+
+use crate::errno::Errno;
+use crate::shm::{shm_close, shm_open, shm_resize, SHM_DIR};
+use crate::tty::{tty_lflag, tty_read, tty_set_lflag, tty_write};
+use alloc::vec::Vec;
+
+pub const MAX_FILES: usize = 16;
+
+// Flags of openat, same values as Linux
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+
+// The FileTable holding a file must call `close` once it is removed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum File {
+    Console,
+    Shm { id: usize },
+}
+
+// Same layout as the termios of Linux (used by TCGETS and TCSETS), only c_lflag is supported
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; 19],
+}
+
+impl File {
+    // Finds the file of an absolute path, only a shared memory object can be created (with O_CREAT)
+    pub fn open(path: &str, flags: u64) -> Result<Self, Errno> {
+        if path == "/dev/console" {
+            return Ok(File::Console);
+        }
+        let Some(name) = path.strip_prefix(SHM_DIR) else {
+            return Err(Errno::ENOENT);
+        };
+        let id = shm_open(name, flags & O_CREAT != 0, flags & O_EXCL != 0)?;
+        let file = File::Shm { id };
+        if flags & O_TRUNC != 0 {
+            if let Err(e) = shm_resize(id, 0) {
+                file.close();
+                return Err(e);
+            }
+        }
+        Ok(file)
+    }
+
+    pub fn close(&self) {
+        match self {
+            File::Console => {}
+            File::Shm { id } => shm_close(*id),
+        }
+    }
+
+    // May sleep, the cpu must not be locked
+    pub fn read(&self, len: usize) -> Result<Vec<u8>, Errno> {
+        match self {
+            File::Console => tty_read(len),
+            File::Shm { .. } => Err(Errno::EINVAL),
+        }
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<usize, Errno> {
+        match self {
+            File::Console => {
+                tty_write(data);
+                Ok(data.len())
+            }
+            File::Shm { .. } => Err(Errno::EINVAL),
+        }
+    }
+
+    pub fn truncate(&self, len: u64) -> Result<(), Errno> {
+        match self {
+            File::Console => Err(Errno::EINVAL),
+            File::Shm { id } => shm_resize(*id, len),
+        }
+    }
+
+    pub fn get_termios(&self) -> Result<Termios, Errno> {
+        match self {
+            File::Console => Ok(Termios {
+                c_lflag: tty_lflag(),
+                ..Termios::default()
+            }),
+            File::Shm { .. } => Err(Errno::ENOTTY),
+        }
+    }
+
+    pub fn set_termios(&self, termios: &Termios) -> Result<(), Errno> {
+        match self {
+            File::Console => {
+                tty_set_lflag(termios.c_lflag);
+                Ok(())
+            }
+            File::Shm { .. } => Err(Errno::ENOTTY),
+        }
+    }
+}
+
+pub(crate) struct FileTable {
+    files: Vec<Option<File>>,
+}
+
+impl FileTable {
+    // stdin, stdout and stderr are the console
+    pub fn new_console() -> Self {
+        Self {
+            files: Vec::from([Some(File::Console); 3]),
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<File, Errno> {
+        self.files
+            .get(fd as usize)
+            .copied()
+            .flatten()
+            .ok_or(Errno::EBADF)
+    }
+
+    // Returns the lowest free file descriptor
+    pub fn insert(&mut self, file: File) -> Result<u64, Errno> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        self.files[fd] = Some(file);
+        Ok(fd as u64)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<(), Errno> {
+        let file = self.files.get_mut(fd as usize).ok_or(Errno::EBADF)?;
+        file.take().ok_or(Errno::EBADF)?.close();
+        Ok(())
+    }
+}
+
+// The files still open when the process exits
+impl Drop for FileTable {
+    fn drop(&mut self) {
+        self.files.iter().flatten().for_each(File::close);
+    }
+}
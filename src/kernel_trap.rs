@@ -1,10 +1,14 @@
 use core::arch::asm;
 use fdt::Fdt;
-use riscv::register::scause::{Interrupt, Scause, Trap};
-use riscv::register::sstatus::{Sstatus, SPP};
+use crate::cpu::get_cpuid;
+use crate::scheduler::{tick, yield_proc};
+use crate::plic;
+use crate::trapframe::{KernelTrapFrame, RegisterDump};
+use crate::uaccess::search_exception_table;
+use riscv::register::scause::{Exception, Interrupt, Scause, Trap};
+use riscv::register::sstatus::SPP;
 use riscv::register::stvec::TrapMode;
 use spin::Once;
-use sbi_print::println;
 
 core::arch::global_asm!(include_str!("asm/kernelvec.S"));
 
@@ -28,6 +32,11 @@ pub unsafe fn enable_timer(fdt: &Fdt) {
         })
     });
 
+    TICK_INTERVAL.call_once(|| {
+        let timebase_frequency = fdt.cpus().next().unwrap().timebase_frequency() as u64;
+        timebase_frequency / TICK_HZ
+    });
+
     // Enable interrupts to supervisor level (external, timer, software)
     riscv::register::sie::set_sext(); // SEIE
     riscv::register::sie::set_stimer(); // STIE
@@ -41,42 +50,88 @@ unsafe fn write_stimecmp(time: u64) {
 }
 
 unsafe fn timer_init() {
-    const SCHED_INTERVAL: u64 = 10_000_000;
+    let interval = *TICK_INTERVAL.get().unwrap();
     if *SSTC_EXENTION.get().unwrap() {
-        write_stimecmp(riscv::register::time::read64() + SCHED_INTERVAL);
+        write_stimecmp(riscv::register::time::read64() + interval);
     } else {
-        sbi::timer::set_timer(riscv::register::time::read64() + SCHED_INTERVAL).unwrap();
+        sbi::timer::set_timer(riscv::register::time::read64() + interval).unwrap();
     }
 }
 
 static SSTC_EXENTION: Once<bool> = Once::new();
 
-// TODO : disable interrupt during a trap I guess
+// Number of timer interrupts per second
+pub const TICK_HZ: u64 = 100;
+// In units of the time register
+static TICK_INTERVAL: Once<u64> = Once::new();
+
+pub(crate) fn ticks_since_boot() -> u64 {
+    riscv::register::time::read64() / *TICK_INTERVAL.get().unwrap()
+}
+
+// Called on a timer interrupt from user or kernel mode
+// Returns true if the current process has used its time slice
+pub(crate) fn timer_interrupt() -> bool {
+    unsafe {
+        timer_init();
+    }
+    tick()
+}
+
+// Sent by another hart to wake up this one (see send_wakeup_ipi), there is nothing else to do
+pub(crate) fn clear_software_interrupt() {
+    unsafe {
+        asm!("csrc sip, {}", in(reg) 1 << 1); // SSIP
+    }
+}
+
+// A device has raised an interrupt, its driver handles it (see plic.rs)
+pub(crate) fn external_interrupt() {
+    plic::handle_interrupt();
+}
+
+// The hart disables the interrupts when taking a trap, they stay disabled until kernelvec returns
+// Every exception in the kernel is fatal, except a fault in a copy from or to the user memory
 #[no_mangle]
-fn kernel_trap() {
-    let _sepc: usize = riscv::register::sepc::read();
-    let sstatus: Sstatus = riscv::register::sstatus::read();
+extern "C" fn kernel_trap(frame: &mut KernelTrapFrame) {
     let scause: Scause = riscv::register::scause::read();
-    let spp = sstatus.spp();
-    match spp {
-        SPP::Supervisor => println!("Trap from Supervisor"),
-        SPP::User => println!("Trap from User"),
+
+    if let SPP::User = riscv::register::sstatus::read().spp() {
+        kernel_panic(frame, scause, "Trap from user mode in kernelvec");
     }
 
-    println!(
-        "Kernel Trap Code : {} | {}",
-        scause.code(),
-        scause.is_interrupt()
-    );
     match scause.cause() {
-        Trap::Interrupt(i) => {
-            println!("Interrupt: {:?}", i);
-            if i == Interrupt::SupervisorTimer {
-                unsafe {
-                    timer_init();
-                }
+        // Preempt the process running in the kernel, the scheduler itself has no process in its cpu (see tick)
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            if timer_interrupt() {
+                // Other traps may happen on this hart (or another one) while the process is not running,
+                // sepc and sstatus are restored from the frame
+                yield_proc();
             }
         }
-        Trap::Exception(e) => println!("Exception: {:?}", e),
+        Trap::Interrupt(Interrupt::SupervisorSoft) => clear_software_interrupt(),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => external_interrupt(),
+        Trap::Interrupt(_) => kernel_panic(frame, scause, "Unexpected interrupt"),
+        Trap::Exception(
+            Exception::LoadPageFault | Exception::StorePageFault | Exception::LoadFault | Exception::StoreFault,
+        ) => match search_exception_table(frame.sepc as usize) {
+            // A copy from or to the user memory faulted
+            Some(fixup) => frame.sepc = fixup as u64,
+            None => kernel_panic(frame, scause, "Kernel exception"),
+        },
+        Trap::Exception(_) => kernel_panic(frame, scause, "Kernel exception"),
     }
 }
+
+fn kernel_panic(frame: &KernelTrapFrame, scause: Scause, msg: &str) -> ! {
+    panic!(
+        "{}: {:?} on hart {}\nsepc: {:#x} stval: {:#x} sstatus: {:#x}\n{}",
+        msg,
+        scause.cause(),
+        get_cpuid(),
+        frame.sepc,
+        riscv::register::stval::read(),
+        frame.sstatus,
+        RegisterDump(frame.registers())
+    )
+}
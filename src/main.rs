@@ -13,16 +13,32 @@
 extern crate alloc;
 
 mod cpu;
+mod errno;
+mod exec;
+mod file;
 mod kernel_trap;
 mod proc;
+mod proc_table;
+mod plic;
+mod programs;
 mod scheduler;
+mod shm;
 mod start;
+mod swap;
+mod tty;
+mod syscall;
 mod trapframe;
+mod uaccess;
+mod uart;
 mod user_trap;
+mod virtio_blk;
 mod vm;
+mod vma;
+mod wait_queue;
 
 use crate::cpu::{init_cpus, read_tp, write_tp};
 use crate::proc::Proc;
+use crate::programs::INITCODE;
 use crate::scheduler::SCHEDULER;
 use alloc::vec;
 use core::ops::Deref;
@@ -32,18 +48,15 @@ use page_alloc::physical_memory_manager;
 use sbi_print::println;
 use crate::vm::KERNEL_PAGE_TABLE;
 
-const INITCODE: [u8; 32] = [
-    0x13, 0x05, 0xd0, 0x00, 0x93, 0x05, 0x40, 0x01, 0x93, 0x08, 0x00, 0x00, 0x73, 0x00, 0x00, 0x00,
-    0x6f, 0x00, 0x00, 0x00, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x57, 0x6f, 0x72, 0x6c, 0x64, 0x21,
-];
-
 const OS_STACK_SIZE: usize = 65536; // Must be the same as in entry.S
+const MAX_CPUS: usize = 8;
 
+// One stack per hart (see entry.S)
 #[repr(C, align(16))]
-struct Stack([u8; OS_STACK_SIZE]);
+struct Stack([u8; OS_STACK_SIZE * MAX_CPUS]);
 
 #[no_mangle]
-static STACK0: Stack = Stack([0; OS_STACK_SIZE]);
+static STACK0: Stack = Stack([0; OS_STACK_SIZE * MAX_CPUS]);
 
 pub static HART_ID: Once<usize> = Once::new();
 
@@ -80,12 +93,32 @@ fn main(hart_id: usize, dtb: usize) -> ! {
     drop(test);
     drop(test1);
 
+    // The run queues need the time slice
+    scheduler::init_time_slice(&fdt);
+    uaccess::init_uaccess(&fdt);
+
     println!("> Init Cpus");
     init_cpus(&fdt);
 
-    // unsafe {
-    //     kernel_trap::enable_timer(&fdt);
-    // }
+    unsafe {
+        kernel_trap::enable_timer(&fdt);
+    }
+
+    // The device registers must be mapped before the other harts use the kernel page table
+    println!("> Init devices");
+    plic::init_plic(&fdt);
+    plic::init_hart_plic(hart_id);
+    uart::init_uart(&fdt);
+    swap::init_swap(&fdt);
+
+    println!("> Start the other harts");
+    for cpu in fdt.cpus() {
+        let id = cpu.ids().first();
+        assert!(id < MAX_CPUS, "Hart {} has no stack", id);
+        if id != hart_id {
+            sbi::hsm::hart_start(id, start::_secondary_entry as usize, dtb).unwrap();
+        }
+    }
 
     println!("---------- Kernel End ----------");
 
@@ -97,8 +130,27 @@ fn main(hart_id: usize, dtb: usize) -> ! {
     // loop {}
 }
 
+// The other harts start here once the boot hart has initialized everything
+fn secondary_main(hart_id: usize, dtb: usize) -> ! {
+    write_tp(hart_id);
+    unsafe {
+        kernel_trap::setup_trap();
+    }
+    vm::init_hart_paging();
+    plic::init_hart_plic(hart_id);
+
+    let fdt = unsafe { fdt::Fdt::from_ptr(dtb as *const u8).unwrap() };
+    unsafe {
+        kernel_trap::enable_timer(&fdt);
+    }
+
+    println!("> Hart {} started", hart_id);
+    SCHEDULER.schedule()
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    sbi_print::reset_console_output();
     println!("[PANIC]: {:?}", info);
     loop {}
 }
@@ -0,0 +1,145 @@
+// Platform-Level Interrupt Controller, routes the interrupts of the devices to the harts
+// Each driver registers a handler for its interrupt (see register_irq), the interrupts are routed to every hart
+// and the first hart to claim one runs the handler
+
+use crate::cpu::get_cpuid;
+use crate::vm::map_mmio;
+use alloc::vec::Vec;
+use fdt::Fdt;
+use sbi_print::println;
+use spin::Once;
+use spinlock::SpinLock;
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_CONTEXT_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4; // Also used to complete
+
+// Cause of the supervisor external interrupt in interrupts-extended (the machine one is 11)
+const SUPERVISOR_EXTERNAL_CAUSE: u32 = 9;
+
+pub const MAX_PRIORITY: u32 = 7;
+
+pub(crate) type IrqHandler = fn();
+
+struct Plic {
+    base: usize,
+    // Number of interrupt sources, they are numbered from 1
+    ndev: usize,
+    // The supervisor context of each hart (indexed by hart id)
+    contexts: Vec<Option<usize>>,
+}
+
+static PLIC: Once<Plic> = Once::new();
+static IRQ_HANDLERS: SpinLock<Vec<(usize, IrqHandler)>> = SpinLock::new("irq_handlers", Vec::new());
+
+pub fn init_plic(fdt: &Fdt) {
+    let node = fdt
+        .find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"])
+        .expect("No PLIC in the DTB");
+    let region = node.reg().unwrap().next().unwrap();
+    let base = region.starting_address as usize;
+    map_mmio(base as u64, region.size.unwrap());
+    let ndev = node
+        .property("riscv,ndev")
+        .and_then(|ndev| ndev.as_usize())
+        .unwrap();
+
+    // The contexts are given as (interrupt controller of a hart, cause) pairs
+    let mut contexts = Vec::new();
+    let interrupts = node.property("interrupts-extended").unwrap().value;
+    for (ctx, cells) in interrupts.chunks_exact(8).enumerate() {
+        let phandle = u32::from_be_bytes(cells[0..4].try_into().unwrap());
+        let cause = u32::from_be_bytes(cells[4..8].try_into().unwrap());
+        if cause != SUPERVISOR_EXTERNAL_CAUSE {
+            continue;
+        }
+        if let Some(hart_id) = hart_of_interrupt_controller(fdt, phandle) {
+            if contexts.len() <= hart_id {
+                contexts.resize(hart_id + 1, None);
+            }
+            contexts[hart_id] = Some(ctx);
+        }
+    }
+    println!("PLIC at {:#x} with {} sources, contexts: {:?}", base, ndev, contexts);
+    PLIC.call_once(|| Plic { base, ndev, contexts });
+}
+
+// The interrupt controller of a hart is a child of its cpu node
+fn hart_of_interrupt_controller(fdt: &Fdt, phandle: u32) -> Option<usize> {
+    fdt.find_all_nodes("/cpus/cpu").find_map(|cpu| {
+        let controls = cpu.children().any(|child| {
+            child
+                .property("phandle")
+                .and_then(|property| property.as_usize())
+                .is_some_and(|value| value == phandle as usize)
+        });
+        if !controls {
+            return None;
+        }
+        Some(cpu.reg()?.next()?.starting_address as usize)
+    })
+}
+
+// Called on every hart, it takes all the interrupts with a priority above 0
+pub fn init_hart_plic(hart_id: usize) {
+    let plic = PLIC.get().unwrap();
+    if let Some(ctx) = plic.context(hart_id) {
+        plic.write(CONTEXT + CONTEXT_STRIDE * ctx + THRESHOLD, 0);
+    }
+}
+
+// Call `handler` when the source `irq` raises an interrupt, the highest priority is claimed first
+pub(crate) fn register_irq(irq: usize, priority: u32, handler: IrqHandler) {
+    let plic = PLIC.get().unwrap();
+    assert!(irq > 0 && irq <= plic.ndev, "No interrupt source {}", irq);
+    assert!(priority > 0 && priority <= MAX_PRIORITY);
+    IRQ_HANDLERS.lock().push((irq, handler));
+
+    plic.write(PRIORITY + 4 * irq, priority);
+    for ctx in plic.contexts.iter().flatten() {
+        let enable = ENABLE + ENABLE_CONTEXT_STRIDE * ctx + 4 * (irq / 32);
+        plic.write(enable, plic.read(enable) | 1 << (irq % 32));
+    }
+}
+
+// Called on a supervisor external interrupt
+pub(crate) fn handle_interrupt() {
+    let plic = PLIC.get().unwrap();
+    let Some(ctx) = plic.context(get_cpuid()) else {
+        return;
+    };
+    let claim = CONTEXT + CONTEXT_STRIDE * ctx + CLAIM;
+    // Another hart may have claimed it
+    let irq = plic.read(claim) as usize;
+    if irq == 0 {
+        return;
+    }
+    let handler = IRQ_HANDLERS
+        .lock()
+        .iter()
+        .find(|(handler_irq, _)| *handler_irq == irq)
+        .map(|(_, handler)| *handler);
+    match handler {
+        Some(handler) => handler(),
+        None => println!("No handler for the interrupt {}", irq),
+    }
+    plic.write(claim, irq as u32);
+}
+
+impl Plic {
+    fn context(&self, hart_id: usize) -> Option<usize> {
+        self.contexts.get(hart_id).copied().flatten()
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+}
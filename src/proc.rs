@@ -1,15 +1,25 @@
+use crate::cpu::get_cpu;
+use crate::errno::Errno;
+use crate::file::FileTable;
+use crate::proc_table::{find_proc, PROC_TABLE};
+use crate::scheduler::sched;
+use crate::swap::reclaim;
 use crate::trapframe::TrapFrame;
+use crate::vma::{AddressSpace, MemoryMap, Vma, VmaKind};
+use crate::wait_queue::WaitQueue;
 use crate::user_trap::usertrapret;
-use crate::vm::{new_user_page_table, KERNEL_PAGE_TABLE};
+use crate::vm::{new_user_page_table, KERNEL_PAGE_TABLE, USER_STACK_TOP};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use core::usize;
-use page_alloc::{PAGE_ALLOCATOR, PAGE_SIZE};
+use page_alloc::{page_round_up, PAGE_ALLOCATOR, PAGE_SIZE};
 use page_table::entry::addr::VirtualAddr;
 use page_table::entry::perm::PTEPermission;
 use page_table::PageTable;
+use sched_policy::{SchedInfo, Task};
+use spinlock::{pop_off, SpinLock};
 
 core::arch::global_asm!(include_str!("asm/trampoline.S"));
 
@@ -37,39 +47,70 @@ pub struct ProcContext {
 pub(crate) struct PageTableAddr(pub NonNull<PageTable>);
 unsafe impl Send for PageTableAddr {}
 
+// The first process, it adopts the orphaned processes
+pub const INIT_PID: usize = 0;
+
 pub(crate) struct Proc {
     // TODO : Add other things
     pub state: ProcState,
     pub context: ProcContext,
+    // The hart running the process or whose run queue holds it
+    pub cpu_id: usize,
+    // What the scheduling policy knows about the process
+    pub sched: SchedInfo,
+    // Where the process goes once it has switched out when Sleeping
+    pub wait_queue: Option<NonNull<WaitQueue>>,
 
     pub name: String,
     pub pid: usize,
+    // Given to the PROC_TABLE once the process is off its kernel stack
+    pub exit_status: i32,
 
     pub kernel_stack: VirtualAddr,
-    // pub memory_size: u64,
-    pub page_table: Box<PageTable>,
+    // The program break: the end of the heap (not page aligned), moved with brk
+    pub memory_size: u64,
+    // The page table and what the process can access in it
+    pub memory: Arc<SpinLock<AddressSpace>>,
     pub trap_frame: Box<TrapFrame>,
+    // Indexed by file descriptor, kept by exec
+    pub files: FileTable,
 }
 unsafe impl Send for Proc {}
 
+impl Task for Proc {
+    fn sched_info(&self) -> &SchedInfo {
+        &self.sched
+    }
+
+    fn sched_info_mut(&mut self) -> &mut SchedInfo {
+        &mut self.sched
+    }
+}
+
 impl Proc {
     pub fn init_user_proc(code: &[u8]) -> Self {
+        reclaim();
         let kstack = usize::from(PAGE_ALLOCATOR.kalloc().unwrap().addr()) as u64;
         // let trap_frame = NonNull::new(unsafe { &mut *(PAGE_ALLOCATOR.kalloc().unwrap().cast().as_ptr()) }).unwrap();
         let trap_frame = Box::new(TrapFrame::new());
         let mut proc = Self {
             state: ProcState::Unused,
             context: ProcContext {
-                ra: usertrapret as usize as u64,
+                ra: proc_entry as usize as u64,
                 sp: kstack + PAGE_SIZE as u64,
                 s: [0; 12],
             },
+            cpu_id: 0,
+            sched: SchedInfo::default(),
+            wait_queue: None,
             name: String::from("Test Proc Name"),
-            pid: get_new_pid(),
+            pid: PROC_TABLE.alloc_pid(None),
+            exit_status: 0,
             kernel_stack: VirtualAddr::new(kstack),
-            // memory_size: 0,
-            page_table: new_user_page_table(unsafe { trap_frame.as_ref() }),
+            memory_size: 0,
+            memory: AddressSpace::new(new_user_page_table(trap_frame.as_ref()), MemoryMap::new()),
             trap_frame,
+            files: FileTable::new_console(),
         };
 
         // Put the code
@@ -82,19 +123,146 @@ impl Proc {
         }
         let ptr_code = VirtualAddr::new(ptr as usize as u64);
         let (pa, _) = KERNEL_PAGE_TABLE.lock().get_phys_addr_perm(&ptr_code);
-        proc.page_table.as_mut().map_pages(
+        let mut memory = proc.memory.lock();
+        memory.page_table.as_mut().map_pages(
             VirtualAddr::new(0),
             pa,
             PAGE_SIZE,
             PTEPermission::read() | PTEPermission::execute() | PTEPermission::user(),
             0,
         );
+        memory.memory_map.insert(Vma {
+            start: 0,
+            end: PAGE_SIZE as u64,
+            perm: PTEPermission::read() | PTEPermission::execute(),
+            kind: VmaKind::Code,
+        });
+        memory.memory_map.insert_heap(PAGE_SIZE as u64);
+        memory.memory_map.insert_stack();
+        drop(memory);
+        proc.memory_size = PAGE_SIZE as u64;
+        proc.trap_frame.sp = USER_STACK_TOP;
 
         proc
     }
+
+    // Move the program break by `increment` bytes and returns the previous one
+    pub fn sbrk(&mut self, increment: i64) -> Result<u64, Errno> {
+        let old_break = self.memory_size;
+        let new_break = old_break.checked_add_signed(increment).ok_or(Errno::ENOMEM)?;
+        let mut memory = self.memory.lock();
+        let memory = &mut *memory;
+        memory
+            .memory_map
+            .resize_heap(&mut memory.page_table, page_round_up(new_break))?;
+        self.memory_size = new_break;
+        Ok(old_break)
+    }
+}
+
+// Where a new process starts running in the kernel, switched to by the scheduler
+extern "C" fn proc_entry() -> ! {
+    // The push_off done by the scheduler before switching to this process
+    pop_off();
+    unsafe { usertrapret() }
+}
+
+// The kernel stack can only be freed once the process is no more running on it
+// The user memory is freed with the AddressSpace
+impl Drop for Proc {
+    fn drop(&mut self) {
+        PAGE_ALLOCATOR.kfree(NonNull::new(*self.kernel_stack.get() as *mut u8).unwrap());
+    }
+}
+
+pub(crate) fn current_pid() -> usize {
+    get_cpu().proc.as_ref().unwrap().pid
+}
+
+// Signal numbers of Linux, a signal always kills the process (there are no handlers)
+pub const SIGINT: i32 = 2;
+pub const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+pub const SIGBUS: i32 = 7;
+pub const SIGSEGV: i32 = 11;
+
+// The process is killed the next time it returns to user mode, a blocking syscall returns EINTR
+// (the TTY readers are woken up by the TTY, wait is woken up here)
+pub(crate) fn send_signal(pid: usize, signal: i32) -> Result<(), Errno> {
+    find_proc(pid, |proc| {
+        if proc.exit_status.is_none() {
+            proc.pending_signal.get_or_insert(signal);
+            proc.child_exit.wake_all();
+        }
+    })
+    .ok_or(Errno::ESRCH)
+}
+
+pub(crate) fn pending_signal(pid: usize) -> Option<i32> {
+    find_proc(pid, |proc| proc.pending_signal).flatten()
+}
+
+// Called before returning to user mode
+pub(crate) fn deliver_signal() {
+    if let Some(signal) = pending_signal(current_pid()) {
+        kill_current(signal)
+    }
+}
+
+pub(crate) fn exit(status: i32) -> ! {
+    // Same encoding as the wait status of Linux
+    exit_with_wait_status((status & 0xff) << 8)
+}
+
+// Terminate the current process as if it had been killed by `signal`
+pub(crate) fn kill_current(signal: i32) -> ! {
+    exit_with_wait_status(signal & 0x7f)
+}
+
+fn exit_with_wait_status(wait_status: i32) -> ! {
+    let mut cpu = get_cpu();
+    let proc = cpu.proc.as_mut().unwrap();
+    assert_ne!(proc.pid, INIT_PID, "init exited with wait status {:#x}", wait_status);
+    proc.exit_status = wait_status;
+    // The scheduler frees the process once it is off its kernel stack
+    proc.state = ProcState::Zombie;
+    drop(cpu);
+    sched();
+    unreachable!("A zombie process has been scheduled")
 }
 
-fn get_new_pid() -> usize {
-    static PID_COUNT: AtomicUsize = AtomicUsize::new(0);
-    PID_COUNT.fetch_add(1, Ordering::AcqRel)
+// Wait for a child to exit (a specific one if `pid` is set) and returns its pid and exit status
+// Fails with EINTR if the process has received a signal
+pub(crate) fn wait(pid: Option<usize>) -> Result<(usize, i32), Errno> {
+    let my_pid = current_pid();
+    loop {
+        let mut proc_table = PROC_TABLE.lock();
+        let children: Vec<usize> = proc_table
+            .find_proc(my_pid)
+            .unwrap()
+            .children
+            .iter()
+            .copied()
+            .filter(|&child| pid.is_none_or(|pid| pid == child))
+            .collect();
+        if children.is_empty() {
+            return Err(Errno::ECHILD);
+        }
+        if let Some(child) = children
+            .into_iter()
+            .find(|&child| proc_table.find_proc(child).unwrap().exit_status.is_some())
+        {
+            return Ok((child, proc_table.reap(child)));
+        }
+        // Checked with the table locked, send_signal cannot wake up this process before it sleeps
+        if proc_table.find_proc(my_pid).unwrap().pending_signal.is_some() {
+            return Err(Errno::EINTR);
+        }
+
+        // Woken up when a child has exited, the queue lives as long as this process
+        let child_exit: *const WaitQueue = proc_table.find_proc(my_pid).unwrap().child_exit.as_ref();
+        unsafe {
+            (*child_exit).sleep(proc_table);
+        }
+    }
 }
@@ -0,0 +1,104 @@
+use crate::wait_queue::WaitQueue;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use sched_policy::ALL_CPUS;
+use spinlock::{SpinLock, SpinLockGuard};
+
+// What the kernel knows about a process wherever it is (running, in the scheduler or a zombie)
+pub(crate) struct ProcInfo {
+    pub pid: usize,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    // Set once the process has exited and its memory has been released, it can then be reaped
+    pub exit_status: Option<i32>,
+    // Where the process waits for its children to exit (boxed so that it does not move with the table)
+    pub child_exit: Box<WaitQueue>,
+    // Harts the process can run on, copied in the process by the scheduler when it is picked
+    pub affinity: u64,
+    // Signal sent to the process, it is killed when it returns to user mode (see deliver_signal)
+    pub pending_signal: Option<i32>,
+}
+
+pub(crate) static PROC_TABLE: ProcTable = ProcTable::new();
+
+// Indexed by pid, a pid is reused once its process has been reaped
+pub(crate) struct ProcTable(SpinLock<Vec<Option<ProcInfo>>>);
+
+impl ProcTable {
+    const fn new() -> Self {
+        Self(SpinLock::new("proc_table", Vec::new()))
+    }
+
+    pub fn alloc_pid(&self, parent: Option<usize>) -> usize {
+        let mut procs = self.0.lock();
+        let pid = match procs.iter().position(Option::is_none) {
+            Some(pid) => pid,
+            None => {
+                procs.push(None);
+                procs.len() - 1
+            }
+        };
+        procs[pid] = Some(ProcInfo {
+            pid,
+            parent,
+            children: Vec::new(),
+            exit_status: None,
+            child_exit: Box::new(WaitQueue::new()),
+            affinity: ALL_CPUS,
+            pending_signal: None,
+        });
+        if let Some(parent) = parent {
+            procs[parent].as_mut().unwrap().children.push(pid);
+        }
+        pid
+    }
+
+    // Lock the table, this is the lock to hold when changing the links between processes
+    pub fn lock(&self) -> ProcTableGuard<'_> {
+        ProcTableGuard(self.0.lock())
+    }
+}
+
+pub(crate) struct ProcTableGuard<'a>(SpinLockGuard<'a, Vec<Option<ProcInfo>>>);
+
+impl ProcTableGuard<'_> {
+    pub fn find_proc(&mut self, pid: usize) -> Option<&mut ProcInfo> {
+        self.0.get_mut(pid)?.as_mut()
+    }
+
+    // Visit every process in the table, in pid order
+    pub fn for_each_proc(&mut self, f: impl FnMut(&mut ProcInfo)) {
+        self.0.iter_mut().flatten().for_each(f);
+    }
+
+    // Give the children of `pid` to `new_parent`
+    pub fn reparent_children(&mut self, pid: usize, new_parent: usize) {
+        let children = core::mem::take(&mut self.find_proc(pid).unwrap().children);
+        for &child in children.iter() {
+            self.find_proc(child).unwrap().parent = Some(new_parent);
+        }
+        self.find_proc(new_parent).unwrap().children.extend(children);
+    }
+
+    // Remove an exited process from the table and returns its exit status, its pid can then be reused
+    pub fn reap(&mut self, pid: usize) -> i32 {
+        let proc = self.0[pid].take().unwrap();
+        debug_assert_eq!(proc.pid, pid);
+        let exit_status = proc.exit_status.expect("Reaping a process which has not exited");
+        assert!(proc.children.is_empty());
+        if let Some(parent) = proc.parent {
+            self.find_proc(parent).unwrap().children.retain(|&child| child != pid);
+        }
+        exit_status
+    }
+}
+
+pub(crate) fn find_proc<R>(pid: usize, f: impl FnOnce(&mut ProcInfo) -> R) -> Option<R> {
+    PROC_TABLE.lock().find_proc(pid).map(f)
+}
+
+// For the syscalls looking at every process (such as kill(-1)), none of them is implemented yet
+#[allow(dead_code)]
+pub(crate) fn for_each_proc(f: impl FnMut(&mut ProcInfo)) {
+    PROC_TABLE.lock().for_each_proc(f)
+}
@@ -0,0 +1,16 @@
+// Programs embedded in the kernel, until there is a file system to load them from
+// They are flat binaries loaded at the virtual address 0 with their entry point at 0
+
+pub const INITCODE: [u8; 32] = [
+    0x13, 0x05, 0xd0, 0x00, 0x93, 0x05, 0x40, 0x01, 0x93, 0x08, 0x00, 0x00, 0x73, 0x00, 0x00, 0x00,
+    0x6f, 0x00, 0x00, 0x00, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x57, 0x6f, 0x72, 0x6c, 0x64, 0x21,
+];
+
+static PROGRAMS: [(&str, &[u8]); 1] = [("/init", &INITCODE)];
+
+pub fn find_program(path: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(name, _)| *name == path)
+        .map(|(_, code)| *code)
+}
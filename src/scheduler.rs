@@ -1,10 +1,24 @@
-use crate::cpu::get_cpu;
-use crate::proc::{Proc, ProcContext, ProcState};
+use crate::cpu::{
+    cpu_count, get_cpu, get_cpuid, is_idle, run_queue, send_wakeup_ipi, set_idle, try_get_cpu,
+};
+use crate::kernel_trap::{ticks_since_boot, TICK_HZ};
+use crate::proc::{Proc, ProcContext, ProcState, INIT_PID};
+use crate::proc_table::{find_proc, PROC_TABLE};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::ops::DerefMut;
-use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fdt::Fdt;
+use sched_policy::{SchedPolicy, Task};
 use sbi_print::println;
+use spinlock::{interrupt_base, interrupts_enabled, pop_off, push_count, push_off, set_interrupt_base};
+
+// The policy deciding which runnable process runs next, the simple one or the MLFQ with the `mlfq` feature
+// The run queue of each hart is an instance of the policy (see cpu.rs)
+#[cfg(not(feature = "mlfq"))]
+pub(crate) type RunQueue = sched_policy::Fifo<Proc>;
+#[cfg(feature = "mlfq")]
+pub(crate) type RunQueue = sched_policy::Mlfq<Proc>;
 
 core::arch::global_asm!(include_str!("asm/switch.S"));
 
@@ -13,51 +27,271 @@ extern "Rust" {
     fn switch(ctx1: *mut ProcContext, ctx2: *mut ProcContext);
 }
 
+// A timer interrupt in the middle would switch again from a half saved context
+// The state restored by the last pop_off belongs to the code switching, it is kept across the switch
+unsafe fn switch_context(old: *mut ProcContext, new: *mut ProcContext) {
+    assert!(!interrupts_enabled(), "switch with the interrupts enabled");
+    let interrupt_base = interrupt_base();
+    switch(old, new);
+    set_interrupt_base(interrupt_base);
+}
+
 pub static SCHEDULER: Scheduler = Scheduler::new();
 
-pub struct Scheduler {
-    used: Mutex<Vec<Proc>>,
+const DEFAULT_TIME_SLICE_MS: usize = 50;
+// In timer ticks, can be changed with `time_slice_ms=<ms>` in the kernel command line (bootargs)
+static TIME_SLICE: AtomicUsize = AtomicUsize::new(ms_to_ticks(DEFAULT_TIME_SLICE_MS));
+
+pub(crate) fn base_time_slice() -> usize {
+    TIME_SLICE.load(Ordering::Relaxed)
 }
 
+const fn ms_to_ticks(ms: usize) -> usize {
+    let ticks = ms * TICK_HZ as usize / 1000;
+    if ticks == 0 {
+        1
+    } else {
+        ticks
+    }
+}
+
+pub fn init_time_slice(fdt: &Fdt) {
+    let time_slice_ms = fdt
+        .chosen()
+        .bootargs()
+        .and_then(|args| {
+            args.split_whitespace()
+                .find_map(|arg| arg.strip_prefix("time_slice_ms="))
+        })
+        .and_then(|ms| ms.parse().ok());
+    if let Some(ms) = time_slice_ms {
+        TIME_SLICE.store(ms_to_ticks(ms), Ordering::Relaxed);
+    }
+    println!(
+        "Time slice: {} ticks of {} ms",
+        TIME_SLICE.load(Ordering::Relaxed),
+        1000 / TICK_HZ
+    );
+}
+
+// Locks must be taken in this order: PROC_TABLE -> wait queue -> cpu -> run queue (checked by lockdep in debug builds)
+// The runnable processes are in the run queue of a hart (see cpu.rs), the policy orders them
+// and a hart steals from the busiest hart when its queue is empty
+// The sleeping processes are not in the scheduler but in a WaitQueue
+pub struct Scheduler;
+
 impl Scheduler {
     const fn new() -> Self {
-        Self {
-            used: Mutex::new(Vec::new()),
-        }
+        Self
     }
 
+    // New processes go to the hart with the fewest runnable processes
     pub(crate) fn add_proc(&self, proc: Proc) {
-        let mut used_list = self.used.lock();
-        used_list.push(proc);
+        let cpu_id = least_loaded_cpu(&proc);
+        self.enqueue(cpu_id, proc);
+    }
+
+    // Make `proc` runnable on the hart `cpu_id` and wake up this hart if it is idle
+    // The process is migrated to another hart if its affinity does not allow `cpu_id`
+    pub(crate) fn enqueue(&self, cpu_id: usize, mut proc: Proc) {
+        let cpu_id = if proc.sched.can_run_on(cpu_id) {
+            cpu_id
+        } else {
+            least_loaded_cpu(&proc)
+        };
+        proc.state = ProcState::Runnable;
+        proc.cpu_id = cpu_id;
+        run_queue(cpu_id).lock().enqueue(proc);
+        if cpu_id != get_cpuid() && is_idle(cpu_id) {
+            send_wakeup_ipi(cpu_id);
+        }
+    }
+
+    fn pick_next(&self, cpu_id: usize) -> Option<Proc> {
+        loop {
+            let proc = run_queue(cpu_id).lock().pick_next(ticks_since_boot());
+            let proc = proc.or_else(|| self.steal(cpu_id))?;
+            if let Some(proc) = self.check_affinity(cpu_id, proc) {
+                return Some(proc);
+            }
+        }
+    }
+
+    // Take a process from the hart with the most runnable processes (which can run on this hart)
+    fn steal(&self, cpu_id: usize) -> Option<Proc> {
+        let mut others: Vec<(usize, usize)> = (0..cpu_count())
+            .filter(|&other| other != cpu_id)
+            .map(|other| (run_queue(other).lock().len(), other))
+            .collect();
+        others.sort_unstable_by(|a, b| b.cmp(a));
+        others
+            .into_iter()
+            .find_map(|(_, other)| run_queue(other).lock().steal(cpu_id))
+    }
+
+    // The affinity of `pid` has been changed, if it is queued on a hart it can no more run on
+    // it is moved to an allowed one (a running or sleeping process is moved when it is picked)
+    pub(crate) fn migrate(&self, pid: usize, affinity: u64) {
+        for cpu_id in 0..cpu_count() {
+            if affinity & (1 << cpu_id) != 0 {
+                continue;
+            }
+            let proc = run_queue(cpu_id).lock().remove(|proc| proc.pid == pid);
+            if let Some(mut proc) = proc {
+                proc.sched.affinity = affinity;
+                self.enqueue(cpu_id, proc);
+                return;
+            }
+        }
+    }
+
+    // The affinity may have been changed (see sys_sched_setaffinity) while the process was not running,
+    // returns None if the process has been sent to another hart
+    fn check_affinity(&self, cpu_id: usize, mut proc: Proc) -> Option<Proc> {
+        proc.sched.affinity = find_proc(proc.pid, |info| info.affinity).unwrap();
+        if proc.sched.can_run_on(cpu_id) {
+            return Some(proc);
+        }
+        self.enqueue(cpu_id, proc);
+        None
     }
 
     pub fn schedule(&self) -> ! {
+        let cpu_id = get_cpuid();
         loop {
-            let mut used_list = self.used.lock();
-            match used_list.pop() {
+            // The last process may have switched back with interrupts disabled
+            debug_assert_eq!(push_count(), 0, "The scheduler holds a lock");
+            unsafe {
+                riscv::register::sstatus::set_sie();
+            }
+
+            match self.pick_next(cpu_id) {
                 Some(mut proc) => {
+                    // Its pages cannot be swapped out by another hart while it runs (see swap::reclaim)
+                    proc.memory.lock().running_on = Some(cpu_id);
+                    // Popped by the process once it runs (in sched or proc_entry)
+                    push_off();
                     let mut cpu_guard = get_cpu();
-                    let mut cpu = cpu_guard.deref_mut();
+                    let cpu = cpu_guard.deref_mut();
                     proc.state = ProcState::Running;
+                    proc.sched_info_mut().ticks = 0;
+                    proc.cpu_id = cpu_id;
                     println!("Switching to proc: {}", proc.name);
                     cpu.proc = Some(Box::new(proc));
                     unsafe {
                         let scheduler_ctx = &mut cpu.scheduler_context as *mut ProcContext;
                         let proc_ctx = &mut cpu.proc.as_mut().unwrap().context as *mut ProcContext;
                         drop(cpu_guard);
-                        switch(scheduler_ctx, proc_ctx)
+                        switch_context(scheduler_ctx, proc_ctx)
                     }
-                    let mut cpu_guard = get_cpu();
-                    let cpu = cpu_guard.deref_mut();
-                    let mut used_list = self.used.lock();
-                    let proc = cpu.proc.take().unwrap();
-                    used_list.push(*proc); // Could do `Box::<Proc>::into_inner(proc)` instead
-                    drop(cpu_guard);
+                    // The process is taken out of the cpu before the interrupts are enabled again,
+                    // otherwise a timer tick would preempt it while the scheduler runs (see tick)
+                    let proc = get_cpu().proc.take().unwrap();
+                    // The push_off done by the process in sched
+                    pop_off();
+                    proc.memory.lock().running_on = None;
+                    self.put_back(*proc); // Could do `Box::<Proc>::into_inner(proc)` instead
+                }
+                None => {
+                    set_idle(true);
+                    // A process may have been enqueued before the hart was marked as idle (without an IPI)
+                    if run_queue(cpu_id).lock().is_empty() {
+                        unsafe {
+                            riscv::asm::wfi();
+                        }
+                    }
+                    set_idle(false);
                 }
-                None => unsafe {
-                    riscv::asm::wfi();
-                },
             }
         }
     }
+
+    fn put_back(&self, mut proc: Proc) {
+        match proc.state {
+            ProcState::Zombie => self.bury(proc),
+            ProcState::Sleeping => {
+                run_queue(proc.cpu_id).lock().on_block(&mut proc);
+                let wait_queue = proc.wait_queue.take().unwrap();
+                unsafe {
+                    wait_queue.as_ref().park(proc);
+                }
+            }
+            _ => self.enqueue(proc.cpu_id, proc),
+        }
+    }
+
+    // Called once the process is no more running on its kernel stack
+    fn bury(&self, proc: Proc) {
+        let pid = proc.pid;
+        let exit_status = proc.exit_status;
+        // Release the address space, the kernel stack and the trap frame
+        drop(proc);
+
+        let mut proc_table = PROC_TABLE.lock();
+        let info = proc_table.find_proc(pid).unwrap();
+        info.exit_status = Some(exit_status);
+        let parent = info.parent;
+        // Give the children to init which is woken up if one of them is already a zombie
+        let orphan_zombie = info.children.clone().into_iter().any(|child| {
+            proc_table.find_proc(child).unwrap().exit_status.is_some()
+        });
+        proc_table.reparent_children(pid, INIT_PID);
+
+        if let Some(parent) = parent {
+            proc_table.find_proc(parent).unwrap().child_exit.wake_all();
+        }
+        if orphan_zombie {
+            proc_table.find_proc(INIT_PID).unwrap().child_exit.wake_all();
+        }
+    }
+}
+
+// The process goes back to the hart it was running on
+pub(crate) fn wake(mut proc: Proc) {
+    run_queue(proc.cpu_id).lock().on_wake(&mut proc);
+    SCHEDULER.enqueue(proc.cpu_id, proc);
+}
+
+// The hart allowed by the affinity of `proc` with the fewest runnable processes
+fn least_loaded_cpu(proc: &Proc) -> usize {
+    (0..cpu_count())
+        .filter(|&cpu_id| proc.sched.can_run_on(cpu_id))
+        .min_by_key(|&cpu_id| run_queue(cpu_id).lock().len())
+        .expect("No hart allowed by the affinity")
+}
+
+// Give back the hart to the scheduler
+// The state of the current process must have been changed before
+pub(crate) fn sched() {
+    // Popped by the scheduler once the process has switched out
+    push_off();
+    let mut cpu_guard = get_cpu();
+    let cpu = cpu_guard.deref_mut();
+    let proc_ctx = &mut cpu.proc.as_mut().unwrap().context as *mut ProcContext;
+    let scheduler_ctx = &mut cpu.scheduler_context as *mut ProcContext;
+    drop(cpu_guard);
+    unsafe { switch_context(proc_ctx, scheduler_ctx) }
+    // The push_off done by the scheduler before switching to this process
+    pop_off();
+}
+
+pub(crate) fn yield_proc() {
+    get_cpu().proc.as_mut().unwrap().state = ProcState::Runnable;
+    sched();
+}
+
+// Called on every timer tick, returns true if the current process has used its time slice
+pub(crate) fn tick() -> bool {
+    // If the cpu is locked the interrupted code is in the middle of something, wait for the next tick
+    let Some(mut cpu) = try_get_cpu() else {
+        return false;
+    };
+    let Some(proc) = cpu.proc.as_mut() else {
+        return false;
+    };
+    // Same for the run queue which may also be used by another hart stealing from it
+    match run_queue(get_cpuid()).try_lock() {
+        Some(mut run_queue) => run_queue.on_tick(proc),
+        None => false,
+    }
 }
@@ -0,0 +1,201 @@
+// Shared memory objects (POSIX like): /dev/shm/<name> is opened with openat (see File::open),
+// sized with ftruncate and mapped in several processes with mmap(MAP_SHARED) (see MemoryMap::map_shm)
+// The name is removed with unlink, the pages are freed once the object is unlinked and it is
+// neither open nor mapped anymore
+
+use crate::errno::Errno;
+use crate::swap::reclaim;
+use crate::vm::USER_END;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use page_alloc::{page_round_up, PAGE_ALLOCATOR, PAGE_SIZE};
+use spinlock::SpinLock;
+
+pub const SHM_DIR: &str = "/dev/shm/";
+const MAX_NAME_LEN: usize = 255;
+
+struct ShmObject {
+    id: usize,
+    // None once unlinked
+    name: Option<String>,
+    // Zeroed pages from the PAGE_ALLOCATOR
+    frames: Vec<u64>,
+    // Number of open files and of mapped areas
+    files: usize,
+    mappings: usize,
+}
+
+struct ShmTable {
+    objects: Vec<ShmObject>,
+    next_id: usize,
+}
+
+static SHM_TABLE: SpinLock<ShmTable> = SpinLock::new(
+    "shm",
+    ShmTable {
+        objects: Vec::new(),
+        next_id: 1,
+    },
+);
+
+impl ShmTable {
+    fn find_mut(&mut self, id: usize) -> &mut ShmObject {
+        self.objects.iter_mut().find(|object| object.id == id).unwrap()
+    }
+
+    fn insert(&mut self, name: Option<String>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.objects.push(ShmObject {
+            id,
+            name,
+            frames: Vec::new(),
+            files: 1,
+            mappings: 0,
+        });
+        id
+    }
+
+    // The object is forgotten when it cannot be used anymore
+    fn free_if_unused(&mut self, id: usize) {
+        let i = self.objects.iter().position(|object| object.id == id).unwrap();
+        let object = &self.objects[i];
+        if object.name.is_none() && object.files == 0 && object.mappings == 0 {
+            free_frames(self.objects.swap_remove(i).frames);
+        }
+    }
+}
+
+// The name is what follows SHM_DIR, without any other '/'
+fn check_name(name: &str) -> Result<(), Errno> {
+    if name.is_empty() || name.contains('/') {
+        return Err(Errno::EINVAL);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+// Returns the id of the object `name`, it is created (empty) if `create` is set
+// With `exclusive` it must not exist, shm_close must be called once the file is closed
+pub(crate) fn shm_open(name: &str, create: bool, exclusive: bool) -> Result<usize, Errno> {
+    check_name(name)?;
+    let mut table = SHM_TABLE.lock();
+    let existing = table
+        .objects
+        .iter_mut()
+        .find(|object| object.name.as_deref() == Some(name));
+    match existing {
+        Some(_) if create && exclusive => Err(Errno::EEXIST),
+        Some(object) => {
+            object.files += 1;
+            Ok(object.id)
+        }
+        None if create => Ok(table.insert(Some(String::from(name)))),
+        None => Err(Errno::ENOENT),
+    }
+}
+
+// An object without a name for MAP_SHARED | MAP_ANONYMOUS, shm_close must be called once it is mapped
+pub(crate) fn shm_anonymous(size: u64) -> Result<usize, Errno> {
+    let id = SHM_TABLE.lock().insert(None);
+    if let Err(e) = shm_resize(id, size) {
+        shm_close(id);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+pub(crate) fn shm_close(id: usize) {
+    let mut table = SHM_TABLE.lock();
+    table.find_mut(id).files -= 1;
+    table.free_if_unused(id);
+}
+
+// The name can be used for a new object
+pub(crate) fn shm_unlink(name: &str) -> Result<(), Errno> {
+    check_name(name)?;
+    let mut table = SHM_TABLE.lock();
+    let object = table
+        .objects
+        .iter_mut()
+        .find(|object| object.name.as_deref() == Some(name))
+        .ok_or(Errno::ENOENT)?;
+    object.name = None;
+    let id = object.id;
+    table.free_if_unused(id);
+    Ok(())
+}
+
+// Change the size of the object (rounded up to whole pages), it cannot shrink while it is mapped
+pub(crate) fn shm_resize(id: usize, size: u64) -> Result<(), Errno> {
+    // Checked before rounding, an object cannot be larger than the user memory
+    if size > USER_END {
+        return Err(Errno::EINVAL);
+    }
+    let pages = (page_round_up(size) / PAGE_SIZE as u64) as usize;
+    let old_pages = SHM_TABLE.lock().find_mut(id).frames.len();
+
+    // The pages are allocated without holding the table, another resize is checked for below
+    if pages > old_pages {
+        reclaim();
+    }
+    let mut new_frames = Vec::new();
+    for _ in old_pages..pages {
+        match PAGE_ALLOCATOR.kalloc() {
+            Ok(frame) => new_frames.push(usize::from(frame.addr()) as u64),
+            Err(_) => {
+                free_frames(new_frames);
+                return Err(Errno::ENOMEM);
+            }
+        }
+    }
+
+    let mut table = SHM_TABLE.lock();
+    let object = table.find_mut(id);
+    if object.frames.len() != old_pages {
+        drop(table);
+        free_frames(new_frames);
+        return Err(Errno::EBUSY);
+    }
+    if pages < old_pages {
+        if object.mappings > 0 {
+            return Err(Errno::EBUSY);
+        }
+        let removed = object.frames.split_off(pages);
+        drop(table);
+        free_frames(removed);
+    } else {
+        object.frames.extend(new_frames);
+    }
+    Ok(())
+}
+
+// Returns the `pages` pages from `offset` (in pages) to map, shm_detach must be called once they are unmapped
+pub(crate) fn shm_attach(id: usize, offset: usize, pages: usize) -> Result<Vec<u64>, Errno> {
+    let mut table = SHM_TABLE.lock();
+    let object = table.find_mut(id);
+    let end = offset.checked_add(pages).ok_or(Errno::EINVAL)?;
+    let frames = object.frames.get(offset..end).ok_or(Errno::EINVAL)?.to_vec();
+    object.mappings += 1;
+    Ok(frames)
+}
+
+// An area mapping the object has been split in two
+pub(crate) fn shm_dup_mapping(id: usize) {
+    SHM_TABLE.lock().find_mut(id).mappings += 1;
+}
+
+pub(crate) fn shm_detach(id: usize) {
+    let mut table = SHM_TABLE.lock();
+    table.find_mut(id).mappings -= 1;
+    table.free_if_unused(id);
+}
+
+fn free_frames(frames: Vec<u64>) {
+    for frame in frames {
+        PAGE_ALLOCATOR.kfree(NonNull::new(frame as *mut u8).unwrap());
+    }
+}
@@ -3,6 +3,7 @@ core::arch::global_asm!(include_str!("asm/entry.S"));
 extern "C" {
     static mut _start_bss: u8;
     static mut _end_bss: u8;
+    pub fn _secondary_entry();
 }
 
 #[no_mangle] // This function must have the same name as in entry.S
@@ -13,3 +14,8 @@ pub unsafe extern "C" fn start(hart_id: usize, dtb: usize) -> ! {
 
     crate::main(hart_id, dtb);
 }
+
+#[no_mangle] // This function must have the same name as in entry.S
+pub unsafe extern "C" fn secondary_start(hart_id: usize, dtb: usize) -> ! {
+    crate::secondary_main(hart_id, dtb);
+}
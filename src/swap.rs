@@ -0,0 +1,110 @@
+// Swap of anonymous user pages to a virtio block device
+// When the free pages go below SWAP_LOW_WATERMARK, the kernel allocating pages (for a process or for itself)
+// first writes cold pages of all the processes to the swap (see reclaim and MemoryMap::reclaim),
+// their PTEs keep the slot and the pages are read back on the next page fault (see MemoryMap::handle_page_fault)
+
+use crate::cpu::get_cpuid;
+use crate::errno::Errno;
+use crate::virtio_blk::{VirtioBlk, SECTOR_SIZE};
+use crate::vma::AddressSpace;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use fdt::Fdt;
+use page_alloc::{PAGE_ALLOCATOR, PAGE_SIZE};
+use page_table::PageTable;
+use sbi_print::println;
+use spin::Once;
+use spinlock::SpinLock;
+
+// In pages, reclaiming stops once there are SWAP_HIGH_WATERMARK free pages
+pub const SWAP_LOW_WATERMARK: usize = 256;
+pub const SWAP_HIGH_WATERMARK: usize = 512;
+
+const SECTORS_PER_SLOT: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+struct Swap {
+    device: VirtioBlk,
+    // One bool per slot of a page
+    used: Vec<bool>,
+}
+
+static SWAP: Once<SpinLock<Swap>> = Once::new();
+
+// The address spaces of all the processes, only locked to add one or to take the list
+static ADDRESS_SPACES: SpinLock<Vec<Weak<SpinLock<AddressSpace>>>> = SpinLock::new("address_spaces", Vec::new());
+
+// The swap is disabled if there is no block device
+pub fn init_swap(fdt: &Fdt) {
+    let Some(device) = VirtioBlk::probe(fdt) else {
+        println!("No block device, the swap is disabled");
+        return;
+    };
+    let slots = (device.capacity() / SECTORS_PER_SLOT) as usize;
+    println!("Swap of {} pages", slots);
+    SWAP.call_once(|| SpinLock::new("swap", Swap { device, used: vec![false; slots] }));
+}
+
+pub(crate) fn swap_enabled() -> bool {
+    SWAP.get().is_some()
+}
+
+// Write the page at `page` (identity mapped) to a free slot, returns None if the swap is full
+pub(crate) fn swap_out(page: *const u8) -> Option<u64> {
+    let mut swap = SWAP.get()?.lock();
+    let slot = swap.used.iter().position(|&used| !used)?;
+    let buf = unsafe { core::slice::from_raw_parts(page, PAGE_SIZE) };
+    swap.device.write(slot as u64 * SECTORS_PER_SLOT, buf).ok()?;
+    swap.used[slot] = true;
+    Some(slot as u64)
+}
+
+// Read the slot into `page` (identity mapped), the slot stays used until free_slot
+pub(crate) fn swap_in(slot: u64, page: *mut u8) -> Result<(), Errno> {
+    let mut swap = SWAP.get().unwrap().lock();
+    let buf = unsafe { core::slice::from_raw_parts_mut(page, PAGE_SIZE) };
+    swap.device
+        .read(slot * SECTORS_PER_SLOT, buf)
+        .map_err(|_| Errno::EIO)
+}
+
+pub(crate) fn free_slot(slot: u64) {
+    SWAP.get().unwrap().lock().used[slot as usize] = false;
+}
+
+pub(crate) fn register_address_space(address_space: &Arc<SpinLock<AddressSpace>>) {
+    let mut address_spaces = ADDRESS_SPACES.lock();
+    address_spaces.retain(|address_space| address_space.strong_count() > 0);
+    address_spaces.push(Arc::downgrade(address_space));
+}
+
+// Write cold pages of the processes to the swap if there are few free pages
+// The address spaces already locked (the one of the caller) and those of the processes running on
+// another hart are skipped
+pub(crate) fn reclaim() {
+    if !swap_enabled() || PAGE_ALLOCATOR.free_count() >= SWAP_LOW_WATERMARK {
+        return;
+    }
+    let address_spaces: Vec<Arc<SpinLock<AddressSpace>>> =
+        ADDRESS_SPACES.lock().iter().filter_map(Weak::upgrade).collect();
+    for address_space in address_spaces.iter() {
+        if PAGE_ALLOCATOR.free_count() >= SWAP_HIGH_WATERMARK {
+            break;
+        }
+        let Some(mut address_space) = address_space.try_lock() else {
+            continue;
+        };
+        if address_space.running_on.is_some_and(|hart| hart != get_cpuid()) {
+            continue;
+        }
+        let address_space = &mut *address_space;
+        address_space.memory_map.reclaim(&mut address_space.page_table);
+    }
+}
+
+// Give back the slots of the pages in the swap before the page table is freed
+pub(crate) fn free_swapped_pages(page_table: &mut PageTable) {
+    if swap_enabled() {
+        page_table.drain_swapped(&mut free_slot);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::cpu::get_cpu;
+use crate::errno::Errno;
+use sbi_print::println;
+
+mod file;
+mod memory;
+mod proc;
+
+// Same numbers as Linux on RiscV
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_UNLINKAT: u64 = 35;
+pub const SYS_FTRUNCATE: u64 = 46;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_SCHED_SETAFFINITY: u64 = 122;
+pub const SYS_SCHED_GETAFFINITY: u64 = 123;
+pub const SYS_SCHED_YIELD: u64 = 124;
+pub const SYS_SETPRIORITY: u64 = 140;
+pub const SYS_GETPRIORITY: u64 = 141;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_EXECVE: u64 = 221;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+pub const SYS_WAIT4: u64 = 260;
+
+// Takes the arguments a0 to a5 and returns the value to put in a0
+type SyscallHandler = fn([u64; 6]) -> Result<u64, Errno>;
+
+static SYSCALLS: [(u64, SyscallHandler); 20] = [
+    (SYS_IOCTL, file::sys_ioctl),
+    (SYS_UNLINKAT, file::sys_unlinkat),
+    (SYS_FTRUNCATE, file::sys_ftruncate),
+    (SYS_OPENAT, file::sys_openat),
+    (SYS_CLOSE, file::sys_close),
+    (SYS_READ, file::sys_read),
+    (SYS_WRITE, file::sys_write),
+    (SYS_EXIT, proc::sys_exit),
+    (SYS_SCHED_SETAFFINITY, proc::sys_sched_setaffinity),
+    (SYS_SCHED_GETAFFINITY, proc::sys_sched_getaffinity),
+    (SYS_SCHED_YIELD, proc::sys_sched_yield),
+    (SYS_SETPRIORITY, proc::sys_setpriority),
+    (SYS_GETPRIORITY, proc::sys_getpriority),
+    (SYS_GETPID, proc::sys_getpid),
+    (SYS_BRK, memory::sys_brk),
+    (SYS_MUNMAP, memory::sys_munmap),
+    (SYS_EXECVE, proc::sys_execve),
+    (SYS_MMAP, memory::sys_mmap),
+    (SYS_MPROTECT, memory::sys_mprotect),
+    (SYS_WAIT4, proc::sys_wait4),
+];
+
+// Called from usertrap on an ecall from user mode
+pub(crate) fn syscall() {
+    let (num, args) = {
+        let mut cpu = get_cpu();
+        let trap_frame = cpu.proc.as_mut().unwrap().trap_frame.as_mut();
+        // Return after the ecall instruction
+        trap_frame.epc += 4;
+        let args = [
+            trap_frame.a0,
+            trap_frame.a1,
+            trap_frame.a2,
+            trap_frame.a3,
+            trap_frame.a4,
+            trap_frame.a5,
+        ];
+        (trap_frame.a7, args)
+    };
+
+    let ret = match SYSCALLS.iter().find(|(syscall_num, _)| *syscall_num == num) {
+        Some((_, handler)) => handler(args),
+        None => {
+            println!("Unknown syscall: {}", num);
+            Err(Errno::ENOSYS)
+        }
+    };
+
+    // The handler may have been rescheduled on another hart
+    let mut cpu = get_cpu();
+    let trap_frame = cpu.proc.as_mut().unwrap().trap_frame.as_mut();
+    trap_frame.a0 = match ret {
+        Ok(value) => value,
+        Err(errno) => errno.as_ret(),
+    };
+}
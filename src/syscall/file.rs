@@ -0,0 +1,132 @@
+use crate::cpu::get_cpu;
+use crate::errno::Errno;
+use crate::file::{File, Termios};
+use crate::shm::{shm_unlink, SHM_DIR};
+use crate::uaccess::{check_user_write, copy_from_user, copy_str_from_user, copy_to_user};
+use alloc::vec;
+use core::mem::size_of;
+use page_alloc::PAGE_SIZE;
+
+const MAX_PATH_LEN: usize = 256;
+// A larger write is done partially
+const MAX_WRITE_LEN: usize = PAGE_SIZE;
+
+// Same values as Linux
+const AT_FDCWD: i64 = -100;
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSW: u64 = 0x5403;
+const TCSETSF: u64 = 0x5404;
+
+// The file of `fd` in the current process
+pub(super) fn get_file(fd: u64) -> Result<File, Errno> {
+    get_cpu().proc.as_ref().unwrap().files.get(fd)
+}
+
+// openat(dirfd, path, flags, mode) only absolute paths are supported, the flags other than O_CREAT, O_EXCL
+// and O_TRUNC and the mode are ignored
+pub(super) fn sys_openat(args: [u64; 6]) -> Result<u64, Errno> {
+    let [dirfd, path, flags, ..] = args;
+    let mut cpu = get_cpu();
+    let proc = cpu.proc.as_mut().unwrap();
+    let path = copy_str_from_user(proc, path, MAX_PATH_LEN)?;
+    if !path.starts_with('/') && dirfd as i64 != AT_FDCWD {
+        return Err(Errno::EBADF);
+    }
+    let file = File::open(&path, flags)?;
+    proc.files.insert(file).inspect_err(|_| file.close())
+}
+
+// unlinkat(dirfd, path, flags) only the shared memory objects can be removed
+pub(super) fn sys_unlinkat(args: [u64; 6]) -> Result<u64, Errno> {
+    let [dirfd, path, ..] = args;
+    let path = {
+        let mut cpu = get_cpu();
+        copy_str_from_user(cpu.proc.as_mut().unwrap(), path, MAX_PATH_LEN)?
+    };
+    if !path.starts_with('/') && dirfd as i64 != AT_FDCWD {
+        return Err(Errno::EBADF);
+    }
+    match path.strip_prefix(SHM_DIR) {
+        Some(name) => shm_unlink(name)?,
+        None if path == "/dev/console" => return Err(Errno::EPERM),
+        None => return Err(Errno::ENOENT),
+    }
+    Ok(0)
+}
+
+// ftruncate(fd, len) sets the size of a shared memory object
+pub(super) fn sys_ftruncate(args: [u64; 6]) -> Result<u64, Errno> {
+    let [fd, len, ..] = args;
+    get_file(fd)?.truncate(len)?;
+    Ok(0)
+}
+
+pub(super) fn sys_close(args: [u64; 6]) -> Result<u64, Errno> {
+    let mut cpu = get_cpu();
+    cpu.proc.as_mut().unwrap().files.close(args[0])?;
+    Ok(0)
+}
+
+// read(fd, buf, len) returns the number of bytes read, 0 at the end of file
+pub(super) fn sys_read(args: [u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = args;
+    let file = get_file(fd)?;
+    // Checked first, the input taken from the TTY would be lost
+    {
+        let mut cpu = get_cpu();
+        check_user_write(cpu.proc.as_mut().unwrap(), buf, len as usize)?;
+    }
+    // May sleep, the cpu is not locked
+    let data = file.read(len as usize)?;
+
+    let mut cpu = get_cpu();
+    copy_to_user(cpu.proc.as_mut().unwrap(), buf, &data)?;
+    Ok(data.len() as u64)
+}
+
+// write(fd, buf, len) returns the number of bytes written
+pub(super) fn sys_write(args: [u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = args;
+    let file = get_file(fd)?;
+    let mut data = vec![0; core::cmp::min(len as usize, MAX_WRITE_LEN)];
+    {
+        let mut cpu = get_cpu();
+        copy_from_user(cpu.proc.as_mut().unwrap(), &mut data, buf)?;
+    }
+    file.write(&data).map(|len| len as u64)
+}
+
+// ioctl(fd, request, arg) only the termios requests are supported, the TTY has no output queue
+// so TCSETSW and TCSETSF are the same as TCSETS
+pub(super) fn sys_ioctl(args: [u64; 6]) -> Result<u64, Errno> {
+    let [fd, request, arg, ..] = args;
+    let file = get_file(fd)?;
+    match request {
+        TCGETS => {
+            let termios = file.get_termios()?;
+            let mut cpu = get_cpu();
+            copy_to_user(cpu.proc.as_mut().unwrap(), arg, termios_bytes(&termios))?;
+        }
+        TCSETS | TCSETSW | TCSETSF => {
+            let mut termios = Termios::default();
+            {
+                let mut cpu = get_cpu();
+                let bytes = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        &mut termios as *mut Termios as *mut u8,
+                        size_of::<Termios>(),
+                    )
+                };
+                copy_from_user(cpu.proc.as_mut().unwrap(), bytes, arg)?;
+            }
+            file.set_termios(&termios)?;
+        }
+        _ => return Err(Errno::ENOTTY),
+    }
+    Ok(0)
+}
+
+fn termios_bytes(termios: &Termios) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(termios as *const Termios as *const u8, size_of::<Termios>()) }
+}
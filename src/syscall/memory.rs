@@ -0,0 +1,169 @@
+use crate::cpu::get_cpu;
+use crate::errno::Errno;
+use crate::file::File;
+use crate::shm::{shm_anonymous, shm_close};
+use super::file::get_file;
+use crate::vm::USER_END;
+use crate::vma::{Vma, VmaKind};
+use page_alloc::{page_round_up, PAGE_SIZE};
+use page_table::entry::perm::PTEPermission;
+
+// Same values as Linux
+const PROT_READ: u64 = 0x1;
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// brk(addr) sets the program break and returns the new one, brk(0) only returns it
+// sbrk(increment) is done by the libc on top of it
+// Linux returns the unchanged break on failure, here the error is returned (ENOMEM when out of memory)
+pub(super) fn sys_brk(args: [u64; 6]) -> Result<u64, Errno> {
+    let mut cpu = get_cpu();
+    let proc = cpu.proc.as_mut().unwrap();
+    let addr = args[0];
+    if addr == 0 {
+        return Ok(proc.memory_size);
+    }
+    proc.sbrk(addr.wrapping_sub(proc.memory_size) as i64)?;
+    Ok(proc.memory_size)
+}
+
+// mmap(addr, len, prot, flags, fd, offset) returns the start of the mapping
+// The private anonymous pages are allocated when touched, the files which can be mapped are the
+// shared memory objects (see shm.rs), a private mapping of a file is a copy made now
+// A MAP_SHARED | MAP_ANONYMOUS mapping is backed by a shared memory object without a name
+pub(super) fn sys_mmap(args: [u64; 6]) -> Result<u64, Errno> {
+    let [addr, len, prot, flags, fd, offset] = args;
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+    let perm = prot_to_perm(prot)?;
+    let len = user_len(len)?;
+    let (shm, offset) = if flags & MAP_ANONYMOUS != 0 {
+        let shm = if shared { Some(shm_anonymous(len)?) } else { None };
+        (shm, 0)
+    } else {
+        if !is_page_aligned(offset) {
+            return Err(Errno::EINVAL);
+        }
+        let File::Shm { id } = get_file(fd)? else {
+            return Err(Errno::ENODEV);
+        };
+        (Some(id), (offset / PAGE_SIZE as u64) as usize)
+    };
+
+    let res = map(addr, len, perm, flags, shared, shm, offset);
+    // The mapping keeps the object
+    if let (Some(id), true) = (shm, flags & MAP_ANONYMOUS != 0) {
+        shm_close(id);
+    }
+    res
+}
+
+fn map(
+    addr: u64,
+    len: u64,
+    perm: PTEPermission,
+    flags: u64,
+    shared: bool,
+    shm: Option<usize>,
+    offset: usize,
+) -> Result<u64, Errno> {
+    let mut cpu = get_cpu();
+    let mut memory = cpu.proc.as_mut().unwrap().memory.lock();
+    let memory = &mut *memory;
+    let (memory_map, page_table) = (&mut memory.memory_map, &mut memory.page_table);
+    let start = if flags & MAP_FIXED != 0 {
+        check_user_range(addr, len)?;
+        // Replace what was mapped there
+        memory_map.unmap(page_table, addr, addr + len)?;
+        addr
+    } else if is_page_aligned(addr)
+        && addr.checked_add(len).is_some_and(|end| end <= USER_END)
+        && memory_map.is_free(addr, addr + len)
+    {
+        // The address is only a hint
+        addr
+    } else {
+        memory_map.find_free_range(len).ok_or(Errno::ENOMEM)?
+    };
+    match shm {
+        Some(id) if shared => memory_map.map_shm(page_table, id, offset, start, len, perm)?,
+        Some(id) => memory_map.map_shm_copy(page_table, id, offset, start, len, perm)?,
+        None => memory_map.insert(Vma {
+            start,
+            end: start + len,
+            perm,
+            kind: VmaKind::Mmap,
+        }),
+    }
+    Ok(start)
+}
+
+// munmap(addr, len)
+pub(super) fn sys_munmap(args: [u64; 6]) -> Result<u64, Errno> {
+    let [addr, len, ..] = args;
+    let len = user_len(len)?;
+    check_user_range(addr, len)?;
+    let mut cpu = get_cpu();
+    let mut memory = cpu.proc.as_mut().unwrap().memory.lock();
+    let memory = &mut *memory;
+    memory.memory_map.unmap(&mut memory.page_table, addr, addr + len)?;
+    Ok(0)
+}
+
+// mprotect(addr, len, prot) only for the areas created by mmap
+pub(super) fn sys_mprotect(args: [u64; 6]) -> Result<u64, Errno> {
+    let [addr, len, prot, ..] = args;
+    let perm = prot_to_perm(prot)?;
+    let len = user_len(len)?;
+    check_user_range(addr, len)?;
+    let mut cpu = get_cpu();
+    let mut memory = cpu.proc.as_mut().unwrap().memory.lock();
+    let memory = &mut *memory;
+    memory.memory_map.protect(&mut memory.page_table, addr, addr + len, perm)?;
+    Ok(0)
+}
+
+// A writable page must also be readable on RiscV
+fn prot_to_perm(prot: u64) -> Result<PTEPermission, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut perm = PTEPermission::new();
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        perm = perm | PTEPermission::read();
+    }
+    if prot & PROT_WRITE != 0 {
+        perm = perm | PTEPermission::write();
+    }
+    if prot & PROT_EXEC != 0 {
+        perm = perm | PTEPermission::execute();
+    }
+    Ok(perm)
+}
+
+// The length rounded up to whole pages
+fn user_len(len: u64) -> Result<u64, Errno> {
+    match len {
+        0 => Err(Errno::EINVAL),
+        len if len > USER_END => Err(Errno::ENOMEM),
+        len => Ok(page_round_up(len)),
+    }
+}
+
+fn check_user_range(addr: u64, len: u64) -> Result<(), Errno> {
+    match addr.checked_add(len) {
+        Some(end) if is_page_aligned(addr) && end <= USER_END => Ok(()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+fn is_page_aligned(addr: u64) -> bool {
+    addr.is_multiple_of(PAGE_SIZE as u64)
+}
@@ -0,0 +1,171 @@
+use crate::cpu::get_cpu;
+use crate::errno::Errno;
+use crate::exec::exec;
+use crate::proc::{current_pid, exit, wait, Proc};
+use crate::proc_table::{find_proc, PROC_TABLE};
+use crate::cpu::{get_cpuid, online_cpus, run_queue};
+use crate::scheduler::{yield_proc, SCHEDULER};
+use crate::uaccess::{copy_from_user, copy_str_from_user, copy_to_user};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use sched_policy::{SchedPolicy, MAX_NICE, MIN_NICE};
+
+const MAX_PATH_LEN: usize = 256;
+const MAX_ARG_LEN: usize = 256;
+const MAX_ARG_COUNT: usize = 32;
+// `which` argument of setpriority and getpriority, process groups and users are not supported
+const PRIO_PROCESS: u64 = 0;
+
+pub(super) fn sys_exit(args: [u64; 6]) -> Result<u64, Errno> {
+    exit(args[0] as i32)
+}
+
+pub(super) fn sys_sched_yield(_args: [u64; 6]) -> Result<u64, Errno> {
+    yield_proc();
+    Ok(0)
+}
+
+// sched_setaffinity(pid, len, mask) the mask has one bit per hart
+// A process queued on a hart it can no more run on is moved at once (see Scheduler::migrate)
+pub(super) fn sys_sched_setaffinity(args: [u64; 6]) -> Result<u64, Errno> {
+    let mut mask = [0; size_of::<u64>()];
+    let len = core::cmp::min(args[1] as usize, mask.len());
+    {
+        let mut cpu = get_cpu();
+        let proc = cpu.proc.as_mut().unwrap();
+        copy_from_user(proc, &mut mask[..len], args[2])?;
+    }
+    let affinity = u64::from_le_bytes(mask) & online_cpus();
+    if affinity == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let pid = match args[0] {
+        0 => current_pid(),
+        pid => pid as usize,
+    };
+    find_proc(pid, |info| info.affinity = affinity).ok_or(Errno::ESRCH)?;
+    if pid == current_pid() {
+        let must_move = {
+            let mut cpu = get_cpu();
+            let proc = cpu.proc.as_mut().unwrap();
+            proc.sched.affinity = affinity;
+            !proc.sched.can_run_on(get_cpuid())
+        };
+        // Switch out to be enqueued on an allowed hart
+        if must_move {
+            yield_proc();
+        }
+    } else {
+        SCHEDULER.migrate(pid, affinity);
+    }
+    Ok(0)
+}
+
+// sched_getaffinity(pid, len, mask) returns the size of the mask written
+pub(super) fn sys_sched_getaffinity(args: [u64; 6]) -> Result<u64, Errno> {
+    let pid = match args[0] {
+        0 => current_pid(),
+        pid => pid as usize,
+    };
+    if (args[1] as usize) < size_of::<u64>() {
+        return Err(Errno::EINVAL);
+    }
+    let affinity = find_proc(pid, |info| info.affinity).ok_or(Errno::ESRCH)? & online_cpus();
+    let mut cpu = get_cpu();
+    let proc = cpu.proc.as_mut().unwrap();
+    copy_to_user(proc, args[2], &affinity.to_le_bytes())?;
+    Ok(size_of::<u64>() as u64)
+}
+
+// setpriority(which, who, prio) sets the nice value, `nice` is done with it by the libc
+// Only the calling process can be changed for now
+pub(super) fn sys_setpriority(args: [u64; 6]) -> Result<u64, Errno> {
+    check_priority_target(args[0], args[1])?;
+    let nice = (args[2] as i32).clamp(MIN_NICE, MAX_NICE);
+    let mut cpu = get_cpu();
+    let proc = cpu.proc.as_mut().unwrap();
+    proc.sched.nice = nice;
+    run_queue(get_cpuid()).lock().on_nice(proc);
+    Ok(0)
+}
+
+// getpriority(which, who) returns 20 - nice like Linux so that the value is never negative
+pub(super) fn sys_getpriority(args: [u64; 6]) -> Result<u64, Errno> {
+    check_priority_target(args[0], args[1])?;
+    let nice = get_cpu().proc.as_ref().unwrap().sched.nice;
+    Ok((20 - nice) as u64)
+}
+
+fn check_priority_target(which: u64, who: u64) -> Result<(), Errno> {
+    if which != PRIO_PROCESS {
+        return Err(Errno::EINVAL);
+    }
+    let who = who as usize;
+    if who == 0 || who == current_pid() {
+        Ok(())
+    } else if PROC_TABLE.lock().find_proc(who).is_some() {
+        Err(Errno::EPERM)
+    } else {
+        Err(Errno::ESRCH)
+    }
+}
+
+pub(super) fn sys_getpid(_args: [u64; 6]) -> Result<u64, Errno> {
+    Ok(current_pid() as u64)
+}
+
+// execve(path, argv, envp)
+pub(super) fn sys_execve(args: [u64; 6]) -> Result<u64, Errno> {
+    let (path, argv, envp) = {
+        let mut cpu = get_cpu();
+        let proc = cpu.proc.as_mut().unwrap();
+        (
+            copy_str_from_user(proc, args[0], MAX_PATH_LEN)?,
+            copy_str_array_from_user(proc, args[1])?,
+            copy_str_array_from_user(proc, args[2])?,
+        )
+    };
+
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    // May wait for the swap, the cpu is not locked
+    exec(&path, &argv, &envp).map(|argc| argc as u64)
+}
+
+// wait4(pid, status) the options and rusage arguments are not supported
+pub(super) fn sys_wait4(args: [u64; 6]) -> Result<u64, Errno> {
+    let pid = match args[0] as i64 {
+        -1 => None,
+        pid if pid >= 0 => Some(pid as usize),
+        _ => return Err(Errno::EINVAL),
+    };
+    let (child, status) = wait(pid)?;
+
+    let status_ptr = args[1];
+    if status_ptr != 0 {
+        let mut cpu = get_cpu();
+        let proc = cpu.proc.as_mut().unwrap();
+        copy_to_user(proc, status_ptr, &status.to_ne_bytes())?;
+    }
+    Ok(child as u64)
+}
+
+// Copy a NULL terminated array of strings (a null pointer is an empty array)
+fn copy_str_array_from_user(proc: &mut Proc, src: u64) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if src == 0 {
+        return Ok(strings);
+    }
+    for i in 0..=MAX_ARG_COUNT {
+        let mut ptr = [0; size_of::<u64>()];
+        copy_from_user(proc, &mut ptr, src + (i * size_of::<u64>()) as u64)?;
+        let ptr = u64::from_ne_bytes(ptr);
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        strings.push(copy_str_from_user(proc, ptr, MAX_ARG_LEN)?);
+    }
+    Err(Errno::E2BIG)
+}
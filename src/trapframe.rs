@@ -82,3 +82,86 @@ impl TrapFrame {
         }
     }
 }
+
+impl TrapFrame {
+    // In the order of REGISTER_NAMES
+    pub fn registers(&self) -> [u64; 31] {
+        [
+            self.ra, self.sp, self.gp, self.tp, self.t0, self.t1, self.t2, self.s0, self.s1, self.a0, self.a1,
+            self.a2, self.a3, self.a4, self.a5, self.a6, self.a7, self.s2, self.s3, self.s4, self.s5, self.s6,
+            self.s7, self.s8, self.s9, self.s10, self.s11, self.t3, self.t4, self.t5, self.t6,
+        ]
+    }
+}
+
+// Saved on the kernel stack by kernelvec when a trap happens in the kernel
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelTrapFrame {
+    pub ra: u64,      //   0
+    pub sp: u64,      //   8 before the trap
+    pub gp: u64,      //  16
+    pub tp: u64,      //  24
+    pub t0: u64,      //  32
+    pub t1: u64,      //  40
+    pub t2: u64,      //  48
+    pub s0: u64,      //  56
+    pub s1: u64,      //  64
+    pub a0: u64,      //  72
+    pub a1: u64,      //  80
+    pub a2: u64,      //  88
+    pub a3: u64,      //  96
+    pub a4: u64,      // 104
+    pub a5: u64,      // 112
+    pub a6: u64,      // 120
+    pub a7: u64,      // 128
+    pub s2: u64,      // 136
+    pub s3: u64,      // 144
+    pub s4: u64,      // 152
+    pub s5: u64,      // 160
+    pub s6: u64,      // 168
+    pub s7: u64,      // 176
+    pub s8: u64,      // 184
+    pub s9: u64,      // 192
+    pub s10: u64,     // 200
+    pub s11: u64,     // 208
+    pub t3: u64,      // 216
+    pub t4: u64,      // 224
+    pub t5: u64,      // 232
+    pub t6: u64,      // 240
+    pub sepc: u64,    // 248 written back to sepc when returning
+    pub sstatus: u64, // 256 written back to sstatus when returning
+}
+
+impl KernelTrapFrame {
+    // In the order of REGISTER_NAMES
+    pub fn registers(&self) -> [u64; 31] {
+        [
+            self.ra, self.sp, self.gp, self.tp, self.t0, self.t1, self.t2, self.s0, self.s1, self.a0, self.a1,
+            self.a2, self.a3, self.a4, self.a5, self.a6, self.a7, self.s2, self.s3, self.s4, self.s5, self.s6,
+            self.s7, self.s8, self.s9, self.s10, self.s11, self.t3, self.t4, self.t5, self.t6,
+        ]
+    }
+}
+
+const REGISTER_NAMES: [&str; 31] = [
+    "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// Displays the registers 4 per line
+pub struct RegisterDump(pub [u64; 31]);
+
+impl core::fmt::Display for RegisterDump {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, (name, value)) in REGISTER_NAMES.iter().zip(self.0.iter()).enumerate() {
+            write!(f, "{:>3}: {:#018x}", name, value)?;
+            if i % 4 == 3 || i == REGISTER_NAMES.len() - 1 {
+                writeln!(f)?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+// The console TTY: the line discipline between the UART and the processes reading /dev/console
+// In canonical mode the input is edited and given by lines (backspace, ^U kill the line, ^D end of file),
+// ^C sends SIGINT to the foreground process (the last one which read the console)
+// and the raw mode gives every byte as it is received (see Termios)
+
+use crate::errno::Errno;
+use crate::proc::{current_pid, pending_signal, send_signal, SIGINT};
+use crate::wait_queue::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use sbi_print::print_bytes;
+use spinlock::SpinLock;
+
+// Flags of c_lflag, same values as Linux
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+
+const MAX_LINE_LEN: usize = 1024;
+
+// Echoed for a backspace: go back, overwrite the character and go back again
+const ERASE: &[u8] = b"\x08 \x08";
+
+struct Tty {
+    // ISIG, ICANON and ECHO
+    lflag: u32,
+    // The line being edited in canonical mode
+    line: Vec<u8>,
+    // The input which can be read, one line per element in canonical mode (an empty line is an end of file)
+    ready: VecDeque<Vec<u8>>,
+    foreground: Option<usize>,
+}
+
+static TTY: SpinLock<Tty> = SpinLock::new(
+    "tty",
+    Tty {
+        lflag: ISIG | ICANON | ECHO,
+        line: Vec::new(),
+        ready: VecDeque::new(),
+        foreground: None,
+    },
+);
+// Woken up one at a time when there is input, all of them on ^C
+static TTY_READERS: WaitQueue = WaitQueue::new();
+
+// A byte received by the UART (called from its interrupt handler)
+pub(crate) fn tty_input(c: u8) {
+    let mut tty = TTY.lock();
+    let echo = tty.lflag & ECHO != 0;
+    if tty.lflag & ISIG != 0 && c == CTRL_C {
+        tty.line.clear();
+        if echo {
+            print_bytes(b"^C\n");
+        }
+        // With the TTY locked, a reader cannot check for its signal before it is sent and then sleep
+        if let Some(pid) = tty.foreground {
+            // The process may have exited since
+            let _ = send_signal(pid, SIGINT);
+        }
+        TTY_READERS.wake_all();
+        return;
+    }
+
+    if tty.lflag & ICANON == 0 {
+        if echo {
+            print_bytes(&[c]);
+        }
+        tty.ready.push_back(Vec::from([c]));
+        TTY_READERS.wake_one();
+        return;
+    }
+
+    match c {
+        BACKSPACE | DELETE => {
+            if tty.line.pop().is_some() && echo {
+                print_bytes(ERASE);
+            }
+        }
+        CTRL_U => {
+            if echo {
+                for _ in 0..tty.line.len() {
+                    print_bytes(ERASE);
+                }
+            }
+            tty.line.clear();
+        }
+        CTRL_D => {
+            let line = core::mem::take(&mut tty.line);
+            tty.ready.push_back(line);
+            TTY_READERS.wake_one();
+        }
+        b'\r' | b'\n' => {
+            if echo {
+                print_bytes(b"\n");
+            }
+            let mut line = core::mem::take(&mut tty.line);
+            line.push(b'\n');
+            tty.ready.push_back(line);
+            TTY_READERS.wake_one();
+        }
+        c if tty.line.len() < MAX_LINE_LEN => {
+            if echo {
+                print_bytes(&[c]);
+            }
+            tty.line.push(c);
+        }
+        _ => {}
+    }
+}
+
+// Wait for some input, in canonical mode at most one line is returned (nothing at the end of file)
+// Fails with EINTR if the process has received a signal
+pub(crate) fn tty_read(len: usize) -> Result<Vec<u8>, Errno> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let pid = current_pid();
+    loop {
+        let mut tty = TTY.lock();
+        tty.foreground = Some(pid);
+        if pending_signal(pid).is_some() {
+            // This reader may have been woken up for some input, another one takes it
+            if !tty.ready.is_empty() {
+                TTY_READERS.wake_one();
+            }
+            return Err(Errno::EINTR);
+        }
+        if tty.ready.is_empty() {
+            TTY_READERS.sleep(tty);
+            continue;
+        }
+
+        let mut data = Vec::new();
+        let canonical = tty.lflag & ICANON != 0;
+        while data.len() < len {
+            let Some(mut chunk) = tty.ready.pop_front() else {
+                break;
+            };
+            let n = core::cmp::min(chunk.len(), len - data.len());
+            data.extend(chunk.drain(..n));
+            if !chunk.is_empty() {
+                tty.ready.push_front(chunk);
+            }
+            if canonical {
+                break;
+            }
+        }
+        // The input is given to one reader at a time, the next one takes what is left
+        if !tty.ready.is_empty() {
+            TTY_READERS.wake_one();
+        }
+        return Ok(data);
+    }
+}
+
+// The bytes are written as they are (the terminal decodes the UTF-8)
+pub(crate) fn tty_write(data: &[u8]) {
+    print_bytes(data);
+}
+
+pub(crate) fn tty_lflag() -> u32 {
+    TTY.lock().lflag
+}
+
+// Switching to the raw mode gives the line being edited as it is
+pub(crate) fn tty_set_lflag(lflag: u32) {
+    let mut tty = TTY.lock();
+    tty.lflag = lflag & (ISIG | ICANON | ECHO);
+    if tty.lflag & ICANON == 0 && !tty.line.is_empty() {
+        let line = core::mem::take(&mut tty.line);
+        tty.ready.push_back(line);
+        TTY_READERS.wake_one();
+    }
+}
@@ -0,0 +1,261 @@
+// Access to the user memory from the kernel with copy_from_user, copy_to_user and copy_str_from_user
+// There are two ways to do it:
+// - walk: every page touched is looked up in the user page table so a bad pointer gives EFAULT instead of a panic
+// - direct (the default, faster): the user page table is loaded with sstatus.SUM set and the routines in uaccess.S
+//   do the copy, a page fault in these routines jumps to a fixup (see the exception table) which returns EFAULT
+// The walk is used with `uaccess=walk` in the kernel command line (bootargs)
+// The kernel cannot handle a page fault on the user memory (the cpu is locked) so the pages of the range
+// not touched yet by the process are allocated first (see MemoryMap::fault_in), page by page for a string
+// so that nothing is allocated past its end
+
+use crate::errno::Errno;
+use crate::proc::Proc;
+use crate::vm::{kernel_phys_addr, USER_END};
+use crate::vma::Access;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use fdt::Fdt;
+use page_alloc::{page_round_down, PAGE_SIZE};
+use riscv::register::satp::Mode;
+use spinlock::{pop_off, push_off};
+use page_table::entry::addr::{VirtualAddr, MAX_VIRTUAL_ADDR};
+use page_table::entry::perm::PTEPermission;
+use page_table::PageTable;
+use sbi_print::println;
+
+core::arch::global_asm!(include_str!("asm/uaccess.S"));
+
+extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __strncpy_from_user(dst: *mut u8, src: *const u8, max: usize) -> isize;
+
+    static _start_ex_table: ExceptionTableEntry;
+    static _end_ex_table: ExceptionTableEntry;
+}
+
+static PAGE_WALK: AtomicBool = AtomicBool::new(false);
+
+pub fn init_uaccess(fdt: &Fdt) {
+    let walk = fdt
+        .chosen()
+        .bootargs()
+        .is_some_and(|args| args.split_whitespace().any(|arg| arg == "uaccess=walk"));
+    PAGE_WALK.store(walk, Ordering::Relaxed);
+    println!("User memory access: {}", if walk { "walk" } else { "direct" });
+}
+
+fn page_walk() -> bool {
+    PAGE_WALK.load(Ordering::Relaxed)
+}
+
+pub(crate) fn copy_from_user(proc: &mut Proc, dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    check_user_range(src, dst.len())?;
+    let mut memory = proc.memory.lock();
+    let memory = &mut *memory;
+    memory.memory_map.fault_in(&mut memory.page_table, src, dst.len(), Access::Read)?;
+    if page_walk() {
+        walk_copy_from_user(&memory.page_table, dst, src)
+    } else {
+        direct_copy_from_user(&memory.page_table, dst, src)
+    }
+}
+
+pub(crate) fn copy_to_user(proc: &mut Proc, dst: u64, src: &[u8]) -> Result<(), Errno> {
+    check_user_range(dst, src.len())?;
+    let mut memory = proc.memory.lock();
+    let memory = &mut *memory;
+    memory.memory_map.fault_in(&mut memory.page_table, dst, src.len(), Access::Write)?;
+    if page_walk() {
+        walk_copy_to_user(&memory.page_table, dst, src)
+    } else {
+        direct_copy_to_user(&memory.page_table, dst, src)
+    }
+}
+
+// Fails with EFAULT if copy_to_user could not write to [dst, dst + len), without allocating the pages
+// Used before taking data which would be lost if the copy failed
+pub(crate) fn check_user_write(proc: &mut Proc, dst: u64, len: usize) -> Result<(), Errno> {
+    check_user_range(dst, len)?;
+    proc.memory.lock().memory_map.check_access(dst, len, Access::Write)
+}
+
+// Copy a NUL terminated string of at most `max_len` bytes (without the NUL), fails with ENAMETOOLONG
+// if there is no NUL in the first `max_len` + 1 bytes
+pub(crate) fn copy_str_from_user(proc: &mut Proc, src: u64, max_len: usize) -> Result<String, Errno> {
+    // With the NUL, the string may stop before the end of the user memory
+    let len = core::cmp::min(max_len as u64 + 1, USER_END.saturating_sub(src)) as usize;
+    check_user_range(src, len)?;
+    let mut memory = proc.memory.lock();
+    let memory = &mut *memory;
+    let mut bytes = Vec::new();
+    // The pages after the one holding the NUL are not touched
+    for (va, len) in page_chunks(src, len) {
+        memory.memory_map.fault_in(&mut memory.page_table, va, len, Access::Read)?;
+        let found = if page_walk() {
+            walk_copy_str_chunk(&memory.page_table, va, len, &mut bytes)?
+        } else {
+            direct_copy_str_chunk(&memory.page_table, va, len, &mut bytes)?
+        };
+        if found {
+            return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+        }
+    }
+    Err(Errno::ENAMETOOLONG)
+}
+
+// Written by uaccess.S in the __ex_table section
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+// Returns the kernel address of the user address `va` (valid until the end of its page)
+fn user_to_kernel(page_table: &PageTable, va: u64, perm: PTEPermission) -> Result<*mut u8, Errno> {
+    if va >= MAX_VIRTUAL_ADDR {
+        return Err(Errno::EFAULT);
+    }
+    let (pa, pte_perm) = page_table
+        .translate(&VirtualAddr::new(va))
+        .ok_or(Errno::EFAULT)?;
+    if !pte_perm.contains(perm | PTEPermission::user()) {
+        return Err(Errno::EFAULT);
+    }
+    // User pages come from the PAGE_ALLOCATOR which is identity mapped in the kernel
+    Ok(*pa.get() as *mut u8)
+}
+
+// Split [va, va + len) in chunks that do not cross a page boundary
+fn page_chunks(va: u64, len: usize) -> impl Iterator<Item = (u64, usize)> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        if offset == len {
+            return None;
+        }
+        let chunk_va = va + offset as u64;
+        // The last page of the address space ends at 2^64
+        let page_end = page_round_down(chunk_va).saturating_add(PAGE_SIZE as u64);
+        let chunk_len = core::cmp::min((page_end - chunk_va) as usize, len - offset);
+        offset += chunk_len;
+        Some((chunk_va, chunk_len))
+    })
+}
+
+fn walk_copy_from_user(page_table: &PageTable, dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    let mut copied = 0;
+    for (va, len) in page_chunks(src, dst.len()) {
+        let ptr = user_to_kernel(page_table, va, PTEPermission::read())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, dst[copied..].as_mut_ptr(), len);
+        }
+        copied += len;
+    }
+    Ok(())
+}
+
+fn walk_copy_to_user(page_table: &PageTable, dst: u64, src: &[u8]) -> Result<(), Errno> {
+    let mut copied = 0;
+    for (va, len) in page_chunks(dst, src.len()) {
+        let ptr = user_to_kernel(page_table, va, PTEPermission::write())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(src[copied..].as_ptr(), ptr, len);
+        }
+        copied += len;
+    }
+    Ok(())
+}
+
+// Append the bytes of [va, va + len) (in one page) up to the NUL to `bytes`, returns true if the NUL is found
+fn walk_copy_str_chunk(page_table: &PageTable, va: u64, len: usize, bytes: &mut Vec<u8>) -> Result<bool, Errno> {
+    let ptr = user_to_kernel(page_table, va, PTEPermission::read())?;
+    let chunk = unsafe { core::slice::from_raw_parts(ptr, len) };
+    match chunk.iter().position(|&c| c == 0) {
+        Some(end) => {
+            bytes.extend_from_slice(&chunk[..end]);
+            Ok(true)
+        }
+        None => {
+            bytes.extend_from_slice(chunk);
+            Ok(false)
+        }
+    }
+}
+
+// Returns where to continue if the instruction at `epc` is allowed to fault
+pub(crate) fn search_exception_table(epc: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = &_start_ex_table as *const ExceptionTableEntry;
+        let end = &_end_ex_table as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.insn == epc)
+        .map(|entry| entry.fixup)
+}
+
+// The user range must not reach the kernel which is also mapped in the user page table
+fn check_user_range(va: u64, len: usize) -> Result<(), Errno> {
+    match va.checked_add(len as u64) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+// Run `f` with the user page table loaded and sstatus.SUM set
+// Interrupts are disabled as the process must not be switched out in the middle
+fn with_user_access<R>(page_table: &PageTable, f: impl FnOnce() -> R) -> R {
+    let kernel_satp = riscv::register::satp::read().bits();
+    let user_ppn = kernel_phys_addr(page_table).ppn().get() as usize;
+    push_off();
+    unsafe {
+        riscv::register::satp::set(Mode::Sv39, 0, user_ppn);
+        riscv::asm::sfence_vma_all();
+        riscv::register::sstatus::set_sum();
+    }
+
+    let res = f();
+
+    unsafe {
+        riscv::register::sstatus::clear_sum();
+        riscv::register::satp::write(kernel_satp);
+        riscv::asm::sfence_vma_all();
+    }
+    pop_off();
+    res
+}
+
+fn direct_copy_from_user(page_table: &PageTable, dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    let not_copied = with_user_access(page_table, || unsafe {
+        __copy_user(dst.as_mut_ptr(), src as *const u8, dst.len())
+    });
+    match not_copied {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+fn direct_copy_to_user(page_table: &PageTable, dst: u64, src: &[u8]) -> Result<(), Errno> {
+    let not_copied = with_user_access(page_table, || unsafe {
+        __copy_user(dst as *mut u8, src.as_ptr(), src.len())
+    });
+    match not_copied {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+// Same as walk_copy_str_chunk
+fn direct_copy_str_chunk(page_table: &PageTable, va: u64, len: usize, bytes: &mut Vec<u8>) -> Result<bool, Errno> {
+    let start = bytes.len();
+    bytes.resize(start + len, 0);
+    let copied = with_user_access(page_table, || unsafe {
+        __strncpy_from_user(bytes[start..].as_mut_ptr(), va as *const u8, len)
+    });
+    if copied < 0 {
+        return Err(Errno::EFAULT);
+    }
+    bytes.truncate(start + copied as usize);
+    Ok((copied as usize) < len)
+}
@@ -0,0 +1,205 @@
+// Driver of the 16550 UART, the console once it is initialised (see sbi_print::set_console_output)
+// The output goes through a ring buffer emptied when the UART can take more bytes (on its interrupt),
+// the input is received on interrupts and given to the TTY (see tty.rs)
+
+use crate::plic::register_irq;
+use crate::tty::tty_input;
+use crate::vm::map_mmio;
+use fdt::Fdt;
+use sbi_print::println;
+use spin::Once;
+use spinlock::SpinLock;
+
+// Registers (the reg-shift of QEMU is 0)
+const RHR: usize = 0; // Receive holding register (read)
+const THR: usize = 0; // Transmit holding register (write)
+const IER: usize = 1; // Interrupt enable register
+const FCR: usize = 2; // FIFO control register (write)
+const IIR: usize = 2; // Interrupt identification register (read)
+const LCR: usize = 3; // Line control register
+const LSR: usize = 5; // Line status register
+// With LCR_BAUD_LATCH set
+const DLL: usize = 0;
+const DLM: usize = 1;
+
+const IER_RX_ENABLE: u8 = 1 << 0;
+const IER_TX_ENABLE: u8 = 1 << 1;
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_FIFO_CLEAR: u8 = 3 << 1;
+const LCR_EIGHT_BITS: u8 = 3;
+const LCR_BAUD_LATCH: u8 = 1 << 7;
+const LSR_RX_READY: u8 = 1 << 0;
+const LSR_TX_IDLE: u8 = 1 << 5;
+
+const BUFFER_SIZE: usize = 512;
+
+struct RingBuffer {
+    buf: [u8; BUFFER_SIZE],
+    read: usize,
+    write: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; BUFFER_SIZE],
+            read: 0,
+            write: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read == self.write
+    }
+
+    fn is_full(&self) -> bool {
+        self.write - self.read == BUFFER_SIZE
+    }
+
+    fn push(&mut self, c: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[self.write % BUFFER_SIZE] = c;
+        self.write += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let c = self.buf[self.read % BUFFER_SIZE];
+        self.read += 1;
+        Some(c)
+    }
+}
+
+struct Uart {
+    base: usize,
+    tx: RingBuffer,
+}
+
+static UART: Once<SpinLock<Uart>> = Once::new();
+
+pub fn init_uart(fdt: &Fdt) {
+    let Some(node) = fdt.find_compatible(&["ns16550a"]) else {
+        println!("No UART, the console stays on SBI");
+        return;
+    };
+    let region = node.reg().unwrap().next().unwrap();
+    let base = region.starting_address as usize;
+    map_mmio(base as u64, region.size.unwrap());
+
+    unsafe {
+        write_reg(base, IER, 0);
+        // 38.4K baud
+        write_reg(base, LCR, LCR_BAUD_LATCH);
+        write_reg(base, DLL, 0x03);
+        write_reg(base, DLM, 0x00);
+        write_reg(base, LCR, LCR_EIGHT_BITS);
+        write_reg(base, FCR, FCR_FIFO_ENABLE | FCR_FIFO_CLEAR);
+        // The TX interrupt is enabled while there are bytes to send (see Uart::start)
+        write_reg(base, IER, IER_RX_ENABLE);
+    }
+    UART.call_once(|| {
+        SpinLock::new(
+            "uart",
+            Uart {
+                base,
+                tx: RingBuffer::new(),
+            },
+        )
+    });
+
+    if let Some(irq) = node.interrupts().and_then(|mut irqs| irqs.next()) {
+        register_irq(irq, 1, uart_interrupt);
+    }
+    sbi_print::set_console_output(uart_write);
+}
+
+// Used by println, nothing else must be locked with the UART (it may be used while holding any lock)
+fn uart_write(bytes: &[u8]) {
+    let mut uart = UART.get().unwrap().lock();
+    for &c in bytes {
+        while !uart.tx.push(c) {
+            // Wait for the UART to take some bytes, the interrupts may be disabled
+            uart.wait_tx_idle();
+            uart.start();
+        }
+    }
+    uart.start();
+}
+
+// The UART has received bytes or can send more
+fn uart_interrupt() {
+    // Reading the IIR acknowledges the TX interrupt (a level one), the other causes are cleared by
+    // reading the RHR and writing the THR below
+    unsafe {
+        read_reg(UART.get().unwrap().lock().base, IIR);
+    }
+    let mut received = [0; 16];
+    loop {
+        let mut len = 0;
+        let mut uart = UART.get().unwrap().lock();
+        while len < received.len() {
+            let Some(c) = uart.receive() else {
+                break;
+            };
+            received[len] = c;
+            len += 1;
+        }
+        uart.start();
+        // The TTY echoes the input, so the UART must not be locked
+        drop(uart);
+
+        received[..len].iter().for_each(|&c| tty_input(c));
+        if len < received.len() {
+            return;
+        }
+    }
+}
+
+impl Uart {
+    // Send the bytes of the ring buffer while the UART can take them, the UART interrupts when it can
+    // take more only while some bytes are left (otherwise nothing would clear the interrupt)
+    fn start(&mut self) {
+        while self.lsr() & LSR_TX_IDLE != 0 {
+            let Some(c) = self.tx.pop() else {
+                break;
+            };
+            unsafe { write_reg(self.base, THR, c) }
+        }
+        let ier = if self.tx.is_empty() {
+            IER_RX_ENABLE
+        } else {
+            IER_RX_ENABLE | IER_TX_ENABLE
+        };
+        unsafe { write_reg(self.base, IER, ier) }
+    }
+
+    fn wait_tx_idle(&self) {
+        while self.lsr() & LSR_TX_IDLE == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if self.lsr() & LSR_RX_READY == 0 {
+            return None;
+        }
+        Some(unsafe { read_reg(self.base, RHR) })
+    }
+
+    fn lsr(&self) -> u8 {
+        unsafe { read_reg(self.base, LSR) }
+    }
+}
+
+unsafe fn read_reg(base: usize, offset: usize) -> u8 {
+    core::ptr::read_volatile((base + offset) as *const u8)
+}
+
+unsafe fn write_reg(base: usize, offset: usize, value: u8) {
+    core::ptr::write_volatile((base + offset) as *mut u8, value)
+}
@@ -1,13 +1,17 @@
 use crate::cpu::{get_cpu, get_cpuid};
-use crate::trapframe::TrapFrame;
-use crate::vm::{TRAMPOLINE, TRAPFRAME};
+use crate::kernel_trap::{clear_software_interrupt, external_interrupt, kernelvec, timer_interrupt};
+use crate::scheduler::yield_proc;
+use crate::syscall::syscall;
+use crate::proc::{deliver_signal, kill_current, SIGBUS, SIGILL, SIGSEGV, SIGTRAP};
+use crate::trapframe::{RegisterDump, TrapFrame};
+use crate::vma::Access;
+use crate::vm::{kernel_phys_addr, TRAMPOLINE, TRAPFRAME};
 use bit_field::BitField;
 use riscv::register::satp::Mode;
-use riscv::register::scause::Exception::UserEnvCall;
-use riscv::register::scause::Trap;
+use riscv::register::scause::{Exception, Interrupt, Trap};
 use riscv::register::sstatus::SPP;
 use riscv::register::stvec::TrapMode;
-use page_table::entry::addr::PhysicalAddr;
+use page_alloc::PAGE_SIZE;
 use sbi_print::println;
 
 extern "C" {
@@ -17,7 +21,10 @@ extern "C" {
 }
 
 #[no_mangle]
-pub unsafe fn usertrapret() {
+pub unsafe fn usertrapret() -> ! {
+    // No trap must happen until we are in user mode as stvec will point to uservec
+    riscv::register::sstatus::clear_sie();
+
     let mut cpu = get_cpu();
     let proc = cpu.proc.as_mut().unwrap();
 
@@ -30,7 +37,7 @@ pub unsafe fn usertrapret() {
 
     let mut trapframe: &mut TrapFrame = proc.trap_frame.as_mut();
     trapframe.kernel_satp = riscv::register::satp::read().bits() as u64;
-    trapframe.kernel_sp = *proc.kernel_stack.get();
+    trapframe.kernel_sp = *proc.kernel_stack.get() + PAGE_SIZE as u64;
     trapframe.kernel_trap = usertrap as usize as u64;
     trapframe.kernel_hartid = get_cpuid() as u64;
 
@@ -45,29 +52,97 @@ pub unsafe fn usertrapret() {
     satp.set_bits(44..60, 0); // ASID
     satp.set_bits(
         0..44,
-        PhysicalAddr::new(proc.page_table.as_ref() as *const _ as u64)
-            .ppn()
-            .get(),
+        kernel_phys_addr(proc.memory.lock().page_table.as_ref()).ppn().get(),
     ); // PPN
 
     let userret = *TRAMPOLINE.get() as usize + userret as usize - trampoline as usize;
     let fp = userret as *const ();
-    let code: fn(u64, u64) = core::mem::transmute(fp);
+    let code: fn(u64, u64) -> ! = core::mem::transmute(fp);
     drop(cpu);
     code(*TRAPFRAME.get(), satp)
 }
 
-// TODO : disable interrupt during a trap I guess
-fn usertrap() {
-    println!("USER TRAP");
+// The interrupts are disabled when entering, they are only enabled during a syscall
+// (the locks are interrupt safe so the process can be preempted in the kernel)
+fn usertrap() -> ! {
+    // We are now in the kernel so traps go to kernelvec
+    unsafe {
+        riscv::register::stvec::write(kernelvec as usize, TrapMode::Direct);
+    }
+
+    // Save the user program counter (it may be changed by another trap)
+    get_cpu().proc.as_mut().unwrap().trap_frame.epc = riscv::register::sepc::read() as u64;
+
     let scause = riscv::register::scause::read();
     match scause.cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            if timer_interrupt() {
+                yield_proc();
+            }
+        }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => clear_software_interrupt(),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => external_interrupt(),
         Trap::Interrupt(i) => println!("Received interrupt: {:?}", i),
-        Trap::Exception(e) => {
-            println!("Received Exception: {:?}", e);
-            if e == UserEnvCall {
-                println!("Received a syscall !");
+        Trap::Exception(Exception::UserEnvCall) => {
+            unsafe {
+                riscv::register::sstatus::set_sie();
             }
+            syscall();
         }
+        Trap::Exception(e @ (Exception::LoadPageFault | Exception::StorePageFault | Exception::InstructionPageFault)) => {
+            page_fault(e)
+        }
+        Trap::Exception(e) => kill_faulting_proc(e),
+    }
+
+    deliver_signal();
+    unsafe { usertrapret() }
+}
+
+// The page may be in an area of the process not allocated yet (see vma.rs)
+fn page_fault(exception: Exception) {
+    let access = match exception {
+        Exception::LoadPageFault => Access::Read,
+        Exception::StorePageFault => Access::Write,
+        _ => Access::Execute,
+    };
+    let va = riscv::register::stval::read() as u64;
+    let res = {
+        let mut cpu = get_cpu();
+        let mut memory = cpu.proc.as_mut().unwrap().memory.lock();
+        let memory = &mut *memory;
+        memory.memory_map.handle_page_fault(&mut memory.page_table, va, access)
+    };
+    if res.is_err() {
+        kill_faulting_proc(exception);
+    }
+}
+
+// The riscv crate has no variant for the load address misaligned exception, it gives Unknown
+const LOAD_MISALIGNED: usize = 4;
+
+// The process has done something wrong, only the process is terminated
+fn kill_faulting_proc(exception: Exception) -> ! {
+    let signal = match exception {
+        Exception::IllegalInstruction => SIGILL,
+        Exception::Breakpoint => SIGTRAP,
+        Exception::InstructionMisaligned | Exception::StoreMisaligned => SIGBUS,
+        Exception::Unknown if riscv::register::scause::read().code() == LOAD_MISALIGNED => SIGBUS,
+        _ => SIGSEGV,
+    };
+    {
+        let cpu = get_cpu();
+        let proc = cpu.proc.as_ref().unwrap();
+        let trap_frame = proc.trap_frame.as_ref();
+        println!(
+            "Process {} ({}) killed: {:?} at sepc {:#x} stval {:#x}\n{}",
+            proc.pid,
+            proc.name,
+            exception,
+            trap_frame.epc,
+            riscv::register::stval::read(),
+            RegisterDump(trap_frame.registers())
+        );
     }
+    kill_current(signal)
 }
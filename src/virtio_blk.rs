@@ -0,0 +1,272 @@
+// Driver for a virtio block device on the MMIO transport (version 2, see the virtio 1.1 specification)
+// QEMU uses the legacy version by default, the Makefile.toml passes `-global virtio-mmio.force-legacy=false`
+// There is a single request at a time and its completion is polled, no interrupt is used
+
+use crate::vm::map_mmio;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, Ordering};
+use fdt::Fdt;
+use page_alloc::{PAGE_ALLOCATOR, PAGE_SIZE};
+use sbi_print::println;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_MAGIC: u32 = 0x74726976; // "virt"
+const VIRTIO_DEVICE_BLOCK: u32 = 2;
+
+// Registers (offsets from the base address)
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_CAPACITY: usize = 0x100; // In sectors
+
+// Device status
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+// Bit 32 of the features (bit 0 of the second word)
+const VIRTIO_F_VERSION_1: u32 = 1;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2; // The device writes in the buffer
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+// A request uses 3 descriptors: header, data and status
+const QUEUE_SIZE: usize = 4;
+
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqAvail {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct VirtqUsed {
+    flags: u16,
+    idx: u16,
+    ring: [VirtqUsedElem; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct BlkRequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Everything shared with the device is in one page from the PAGE_ALLOCATOR (identity mapped)
+#[repr(C, align(4096))]
+struct QueuePage {
+    desc: [VirtqDesc; QUEUE_SIZE],
+    avail: VirtqAvail,
+    used: VirtqUsed,
+    header: BlkRequestHeader,
+    status: u8,
+}
+
+#[derive(Debug)]
+pub struct IoError;
+
+pub struct VirtioBlk {
+    base: usize,
+    queue: NonNull<QueuePage>,
+    last_used: u16,
+    capacity: u64,
+}
+// The queue page is only used with the device lock held (see swap.rs)
+unsafe impl Send for VirtioBlk {}
+
+impl VirtioBlk {
+    // The first virtio block device in the DTB
+    pub fn probe(fdt: &Fdt) -> Option<Self> {
+        let mut nodes = fdt.all_nodes().filter(|node| {
+            node.compatible()
+                .is_some_and(|compatible| compatible.all().any(|c| c == "virtio,mmio"))
+        });
+        nodes.find_map(|node| {
+            let region = node.reg()?.next()?;
+            let base = region.starting_address as usize;
+            map_mmio(base as u64, region.size.unwrap_or(PAGE_SIZE));
+            unsafe { Self::init(base) }
+        })
+    }
+
+    // Returns None if there is no block device at `base`
+    unsafe fn init(base: usize) -> Option<Self> {
+        let read = |offset| unsafe { read_reg(base, offset) };
+        let write = |offset, value| unsafe { write_reg(base, offset, value) };
+        if read(MAGIC_VALUE) != VIRTIO_MAGIC || read(DEVICE_ID) != VIRTIO_DEVICE_BLOCK {
+            return None;
+        }
+        if read(VERSION) != 2 {
+            println!("virtio-blk at {:#x}: legacy device not supported", base);
+            return None;
+        }
+
+        write(STATUS, 0); // Reset
+        write(STATUS, STATUS_ACKNOWLEDGE);
+        write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        // No optional feature is needed
+        write(DEVICE_FEATURES_SEL, 1);
+        if read(DEVICE_FEATURES) & VIRTIO_F_VERSION_1 == 0 {
+            return None;
+        }
+        write(DRIVER_FEATURES_SEL, 0);
+        write(DRIVER_FEATURES, 0);
+        write(DRIVER_FEATURES_SEL, 1);
+        write(DRIVER_FEATURES, VIRTIO_F_VERSION_1);
+        write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if read(STATUS) & STATUS_FEATURES_OK == 0 {
+            return None;
+        }
+
+        write(QUEUE_SEL, 0);
+        if (read(QUEUE_NUM_MAX) as usize) < QUEUE_SIZE || read(QUEUE_READY) != 0 {
+            return None;
+        }
+        let queue: NonNull<QueuePage> = PAGE_ALLOCATOR.kalloc().ok()?.cast();
+        let queue_ref = unsafe { queue.as_ref() };
+        write(QUEUE_NUM, QUEUE_SIZE as u32);
+        let set_addr = |low, high, addr: u64| {
+            write(low, addr as u32);
+            write(high, (addr >> 32) as u32);
+        };
+        set_addr(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, &queue_ref.desc as *const _ as u64);
+        set_addr(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, &queue_ref.avail as *const _ as u64);
+        set_addr(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, &queue_ref.used as *const _ as u64);
+        write(QUEUE_READY, 1);
+
+        write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+
+        let capacity = read(CONFIG_CAPACITY) as u64 | (read(CONFIG_CAPACITY + 4) as u64) << 32;
+        Some(Self {
+            base,
+            queue,
+            last_used: 0,
+            capacity,
+        })
+    }
+
+    // In sectors
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    // `buf` must be identity mapped (a page from the PAGE_ALLOCATOR)
+    pub fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), IoError> {
+        self.request(VIRTIO_BLK_T_IN, sector, buf.as_mut_ptr(), buf.len())
+    }
+
+    pub fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), IoError> {
+        self.request(VIRTIO_BLK_T_OUT, sector, buf.as_ptr() as *mut u8, buf.len())
+    }
+
+    fn request(&mut self, kind: u32, sector: u64, buf: *mut u8, len: usize) -> Result<(), IoError> {
+        assert_eq!(len % SECTOR_SIZE, 0);
+        if sector + (len / SECTOR_SIZE) as u64 > self.capacity {
+            return Err(IoError);
+        }
+        let queue = unsafe { self.queue.as_mut() };
+        queue.header = BlkRequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        queue.status = 0xff;
+        queue.desc[0] = VirtqDesc {
+            addr: &queue.header as *const _ as u64,
+            len: core::mem::size_of::<BlkRequestHeader>() as u32,
+            flags: VIRTQ_DESC_F_NEXT,
+            next: 1,
+        };
+        let data_flags = match kind {
+            VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            _ => VIRTQ_DESC_F_NEXT,
+        };
+        queue.desc[1] = VirtqDesc {
+            addr: buf as u64,
+            len: len as u32,
+            flags: data_flags,
+            next: 2,
+        };
+        queue.desc[2] = VirtqDesc {
+            addr: &queue.status as *const _ as u64,
+            len: 1,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+
+        let avail_idx = queue.avail.idx;
+        queue.avail.ring[avail_idx as usize % QUEUE_SIZE] = 0;
+        // The descriptors must be written before the device sees the new index
+        fence(Ordering::SeqCst);
+        unsafe {
+            core::ptr::write_volatile(&mut queue.avail.idx, avail_idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        unsafe {
+            write_reg(self.base, QUEUE_NOTIFY, 0);
+        }
+
+        while unsafe { core::ptr::read_volatile(&queue.used.idx) } == self.last_used {
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        self.last_used = self.last_used.wrapping_add(1);
+        unsafe {
+            let status = read_reg(self.base, INTERRUPT_STATUS);
+            write_reg(self.base, INTERRUPT_ACK, status);
+        }
+
+        match unsafe { core::ptr::read_volatile(&queue.status) } {
+            0 => Ok(()),
+            _ => Err(IoError),
+        }
+    }
+}
+
+unsafe fn read_reg(base: usize, offset: usize) -> u32 {
+    core::ptr::read_volatile((base + offset) as *const u32)
+}
+
+unsafe fn write_reg(base: usize, offset: usize, value: u32) {
+    core::ptr::write_volatile((base + offset) as *mut u32, value)
+}
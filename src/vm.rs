@@ -7,8 +7,9 @@ use fdt::Fdt;
 use page_table::entry::addr::MAX_VIRTUAL_ADDR;
 use page_table::entry::addr::{PhysicalAddr, VirtualAddr};
 use riscv::register::satp::Mode;
-use spin::{Lazy, Mutex};
-use page_alloc::{PAGE_ALLOCATOR, page_round_up, PAGE_SIZE};
+use spin::Lazy;
+use spinlock::SpinLock;
+use page_alloc::{PAGE_ALLOCATOR, page_round_down, page_round_up, PAGE_SIZE};
 use page_table::entry::perm::PTEPermission;
 use page_table::PageTable;
 use sbi_print::println;
@@ -16,16 +17,26 @@ use sbi_print::println;
 pub const TRAMPOLINE: VirtualAddr = VirtualAddr::new(MAX_VIRTUAL_ADDR - PAGE_SIZE as u64);
 pub const TRAPFRAME: VirtualAddr = TRAMPOLINE.sub_offset(PAGE_SIZE as u64);
 
+// The user memory is below this address, above it the kernel is mapped (without the user permission)
+// in every user page table so that the kernel can access the user memory directly (see uaccess.rs)
+pub const USER_END: u64 = 0x80000000;
+// The last GiB holds the trampoline and the trap frame which are not shared
+const KERNEL_SHARED_END: u64 = MAX_VIRTUAL_ADDR - (1 << 30);
+
+// The user stack is at the top of the user memory, its pages are allocated when touched (see vma.rs)
+pub const USER_STACK_TOP: u64 = USER_END;
+pub const USER_STACK_SIZE: usize = 64 * PAGE_SIZE;
+
 extern "C" {
     static _kernel_end_text: u8;
     static _kernel_end: u8;
     static _trampoline: u8;
 }
 
-pub(crate) static KERNEL_PAGE_TABLE: Lazy<Mutex<&mut PageTable>> = Lazy::new(|| {
+pub(crate) static KERNEL_PAGE_TABLE: Lazy<SpinLock<&mut PageTable>> = Lazy::new(|| {
     let kernel_page_table: &mut PageTable =
         unsafe { &mut *(PAGE_ALLOCATOR.kalloc().unwrap().cast().as_ptr()) };
-    Mutex::new(kernel_page_table)
+    SpinLock::new("kernel_page_table", kernel_page_table)
 });
 
 pub fn init_paging(_fdt: &Fdt) {
@@ -139,7 +150,31 @@ pub fn init_paging(_fdt: &Fdt) {
 
     println!("Setup Page Table finished");
 
-    let kernel_page_table_addr = *kernel_page_table.deref() as *const PageTable as u64;
+    drop(kernel_page_table);
+    init_hart_paging();
+
+    println!("Setup Kernel Paging Finished");
+}
+
+// Identity map the registers of a device found in the DTB, before the other harts are started
+pub(crate) fn map_mmio(addr: u64, size: usize) {
+    let start = page_round_down(addr);
+    let end = page_round_up(addr + size as u64);
+    KERNEL_PAGE_TABLE.lock().map_pages(
+        VirtualAddr::new(start),
+        PhysicalAddr::new(start),
+        (end - start) as usize,
+        PTEPermission::read() | PTEPermission::write(),
+        0,
+    );
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+}
+
+// Enable paging on the current hart with the kernel page table
+pub fn init_hart_paging() {
+    let kernel_page_table_addr = *KERNEL_PAGE_TABLE.lock().deref() as *const PageTable as u64;
 
     unsafe {
         // Enable paging
@@ -151,8 +186,6 @@ pub fn init_paging(_fdt: &Fdt) {
         );
         riscv::asm::sfence_vma_all();
     }
-
-    println!("Setup Kernel Paging Finished");
 }
 
 pub(crate) fn new_user_page_table(proc_trap_frame: &TrapFrame) -> Box<PageTable> {
@@ -172,12 +205,29 @@ pub(crate) fn new_user_page_table(proc_trap_frame: &TrapFrame) -> Box<PageTable>
 
     page_table.map_pages(
         TRAPFRAME,
-        PhysicalAddr::new(proc_trap_frame as *const _ as u64),
+        kernel_phys_addr(proc_trap_frame),
         PAGE_SIZE,
         PTEPermission::read() | PTEPermission::write(),
         0,
     );
 
+    // The root entries of the kernel are copied once: everything the kernel maps above USER_END must be
+    // in a GiB already used when the first process is created (the kernel and the heap are mapped in
+    // init_paging and init_heap, the devices are mapped below USER_END)
+    page_table.share_root_entries(
+        &KERNEL_PAGE_TABLE.lock(),
+        VirtualAddr::new(USER_END),
+        VirtualAddr::new(KERNEL_SHARED_END),
+    );
+
     // NonNull::new(page_table).unwrap()
     page_table
 }
+
+// The kernel heap is not identity mapped so we need the kernel page table to find the physical address
+// The value pointed must not cross a page boundary
+pub(crate) fn kernel_phys_addr<T>(ptr: *const T) -> PhysicalAddr {
+    let va = VirtualAddr::new(ptr as usize as u64);
+    let (pa, _) = KERNEL_PAGE_TABLE.lock().get_phys_addr_perm(&va);
+    pa
+}
@@ -0,0 +1,546 @@
+// Virtual memory areas: the ranges of the user address space a process is allowed to use
+// The pages of an area are only allocated when they are touched for the first time (see handle_page_fault)
+// and the anonymous pages may be written to the swap when memory is low (see reclaim)
+
+use crate::errno::Errno;
+use crate::shm::{shm_attach, shm_detach, shm_dup_mapping};
+use crate::swap::{
+    free_slot, free_swapped_pages, reclaim, register_address_space, swap_enabled, swap_in, swap_out,
+    SWAP_HIGH_WATERMARK, SWAP_LOW_WATERMARK,
+};
+use crate::vm::{USER_STACK_SIZE, USER_STACK_TOP};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use page_alloc::{page_round_down, PAGE_ALLOCATOR, PAGE_SIZE};
+use page_table::entry::addr::{PhysicalAddr, VirtualAddr};
+use page_table::entry::perm::PTEPermission;
+use page_table::PageTable;
+use spinlock::SpinLock;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum VmaKind {
+    Code,
+    Heap,
+    Stack,
+    // Private mapping (MAP_PRIVATE), anonymous or a copy of a file
+    Mmap,
+    // Pages of a shared memory object (see shm.rs) mapped with MAP_SHARED, they are not freed when unmapped
+    // A MAP_SHARED | MAP_ANONYMOUS mapping is an object without a name
+    Shm { id: usize },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Vma {
+    // Page aligned, `end` is excluded
+    pub start: u64,
+    pub end: u64,
+    // Without the user permission
+    pub perm: PTEPermission,
+    pub kind: VmaKind,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Vma {
+    pub fn contains(&self, va: u64) -> bool {
+        self.start <= va && va < self.end
+    }
+
+    // The guard page below the stack cannot be used by another area
+    fn reserved_start(&self) -> u64 {
+        match self.kind {
+            VmaKind::Stack => self.start - PAGE_SIZE as u64,
+            _ => self.start,
+        }
+    }
+
+    // The area or its guard page is in [start, end)
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.reserved_start() < end && start < self.end
+    }
+
+    // The pages of the code are not swapped as they could be read again from the program,
+    // the shared mappings neither as their pages would be shared
+    pub fn is_swappable(&self) -> bool {
+        matches!(self.kind, VmaKind::Heap | VmaKind::Stack | VmaKind::Mmap)
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        let perm = match access {
+            Access::Read => PTEPermission::read(),
+            Access::Write => PTEPermission::write(),
+            Access::Execute => PTEPermission::execute(),
+        };
+        self.perm.contains(perm)
+    }
+}
+
+// The memory of a process, also known by the swap so that the cold pages of any process can be reclaimed
+pub(crate) struct AddressSpace {
+    pub page_table: Box<PageTable>,
+    pub memory_map: MemoryMap,
+    // The hart running the process, the pages may be in its TLB (see swap::reclaim)
+    pub running_on: Option<usize>,
+}
+
+impl AddressSpace {
+    pub fn new(page_table: Box<PageTable>, memory_map: MemoryMap) -> Arc<SpinLock<Self>> {
+        let address_space = Arc::new(SpinLock::new(
+            "address_space",
+            Self {
+                page_table,
+                memory_map,
+                running_on: None,
+            },
+        ));
+        register_address_space(&address_space);
+        address_space
+    }
+}
+
+// Freed once neither the process nor a reclaim uses it
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.memory_map.unmap_all_shm(&mut self.page_table);
+        free_swapped_pages(&mut self.page_table);
+        self.page_table.free_user_pages();
+    }
+}
+
+// mmap puts the areas from the top (below the stack and a guard page) down to the heap
+const MMAP_TOP: u64 = USER_STACK_TOP - USER_STACK_SIZE as u64 - PAGE_SIZE as u64;
+
+// The areas of a process, sorted by address and not overlapping
+pub(crate) struct MemoryMap {
+    vmas: Vec<Vma>,
+    // Last page looked at by reclaim (clock algorithm)
+    clock_hand: u64,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self {
+            vmas: Vec::new(),
+            clock_hand: 0,
+        }
+    }
+
+    pub fn find(&self, va: u64) -> Option<&Vma> {
+        self.vmas.iter().find(|vma| vma.contains(va))
+    }
+
+    pub fn insert(&mut self, vma: Vma) {
+        assert!(vma.start.is_multiple_of(PAGE_SIZE as u64) && vma.end.is_multiple_of(PAGE_SIZE as u64));
+        assert!(
+            self.vmas.iter().all(|other| !other.overlaps(vma.reserved_start(), vma.end)),
+            "Overlapping VMA {:x?}",
+            vma
+        );
+        let i = self.vmas.partition_point(|other| other.start < vma.start);
+        self.vmas.insert(i, vma);
+    }
+
+    // The stack is at the top of the user memory, the page below it is never mapped (see Vma::reserved_start)
+    pub fn insert_stack(&mut self) {
+        self.insert(Vma {
+            start: USER_STACK_TOP - USER_STACK_SIZE as u64,
+            end: USER_STACK_TOP,
+            perm: PTEPermission::read() | PTEPermission::write(),
+            kind: VmaKind::Stack,
+        });
+    }
+
+    // Map a zeroed page at `va` (or its page from the swap) if it is in an area allowing `access`
+    // Fails with EFAULT if the process is not allowed to do this access (the page may already be mapped)
+    pub fn handle_page_fault(&mut self, page_table: &mut PageTable, va: u64, access: Access) -> Result<(), Errno> {
+        self.reclaim_all(page_table);
+        self.map_page(page_table, va, access)
+    }
+
+    fn map_page(&self, page_table: &mut PageTable, va: u64, access: Access) -> Result<(), Errno> {
+        let vma = self.find(va).ok_or(Errno::EFAULT)?;
+        if !vma.allows(access) {
+            return Err(Errno::EFAULT);
+        }
+        let perm = vma.perm | PTEPermission::user();
+        let va = VirtualAddr::new(page_round_down(va));
+        if page_table.translate(&va).is_some() {
+            return Err(Errno::EFAULT);
+        }
+        match page_table.swapped_slot(&va) {
+            Some(slot) => {
+                map_swapped_page(page_table, va, perm, slot)?;
+                free_slot(slot);
+                Ok(())
+            }
+            None => map_zeroed_page(page_table, va, perm),
+        }
+    }
+
+    // The heap starts empty just after the code and is changed with brk
+    pub fn insert_heap(&mut self, start: u64) {
+        self.insert(Vma {
+            start,
+            end: start,
+            perm: PTEPermission::read() | PTEPermission::write(),
+            kind: VmaKind::Heap,
+        });
+    }
+
+    pub fn heap(&self) -> &Vma {
+        self.vmas.iter().find(|vma| vma.kind == VmaKind::Heap).unwrap()
+    }
+
+    // Move the end of the heap to `new_end` (page aligned), the pages added are mapped now so that
+    // running out of memory is reported by brk instead of killing the process on a later page fault
+    pub fn resize_heap(&mut self, page_table: &mut PageTable, new_end: u64) -> Result<(), Errno> {
+        let i = self.vmas.iter().position(|vma| vma.kind == VmaKind::Heap).unwrap();
+        let (start, end) = (self.vmas[i].start, self.vmas[i].end);
+        if new_end < start {
+            return Err(Errno::EINVAL);
+        }
+        if new_end > end {
+            // The heap cannot grow into the next area
+            if self.vmas.get(i + 1).is_some_and(|next| new_end > next.reserved_start()) {
+                return Err(Errno::ENOMEM);
+            }
+            let perm = self.vmas[i].perm | PTEPermission::user();
+            self.reclaim_all(page_table);
+            for page in (end..new_end).step_by(PAGE_SIZE) {
+                if let Err(e) = map_zeroed_page(page_table, VirtualAddr::new(page), perm) {
+                    if page > end {
+                        page_table.unmap_pages(VirtualAddr::new(end), (page - end) as usize, true);
+                    }
+                    return Err(e);
+                }
+            }
+        } else if new_end < end {
+            free_swapped_range(page_table, new_end, end);
+            page_table.unmap_pages(VirtualAddr::new(new_end), (end - new_end) as usize, true);
+        }
+        self.vmas[i].end = new_end;
+        Ok(())
+    }
+
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        self.vmas.iter().all(|vma| !vma.overlaps(start, end))
+    }
+
+    // The highest free range of `len` bytes above the heap for mmap
+    pub fn find_free_range(&self, len: u64) -> Option<u64> {
+        let mut top = MMAP_TOP;
+        for vma in self.vmas.iter().rev().filter(|vma| vma.start < MMAP_TOP) {
+            if let Some(start) = top.checked_sub(len).filter(|&start| start >= vma.end) {
+                return Some(start);
+            }
+            top = core::cmp::min(top, vma.reserved_start());
+            if vma.kind == VmaKind::Heap {
+                break;
+            }
+        }
+        None
+    }
+
+    // Unmap [start, end) (page aligned), only the mmap areas can be removed
+    pub fn unmap(&mut self, page_table: &mut PageTable, start: u64, end: u64) -> Result<(), Errno> {
+        self.check_mmap_only(start, end)?;
+        self.split_at(start);
+        self.split_at(end);
+        let (removed, kept) = core::mem::take(&mut self.vmas)
+            .into_iter()
+            .partition(|vma| vma.end > start && vma.start < end);
+        self.vmas = kept;
+        // The pages between the areas are not mapped
+        for vma in removed {
+            unmap_vma(page_table, &vma);
+        }
+        Ok(())
+    }
+
+    // Change the permissions of [start, end) (page aligned) which must be covered by mmap areas
+    pub fn protect(
+        &mut self,
+        page_table: &mut PageTable,
+        start: u64,
+        end: u64,
+        perm: PTEPermission,
+    ) -> Result<(), Errno> {
+        self.check_mmap_only(start, end)?;
+        let mut covered = start;
+        for vma in self.vmas.iter().filter(|vma| vma.end > start && vma.start < end) {
+            if vma.start > covered {
+                break;
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(Errno::ENOMEM);
+        }
+        self.split_at(start);
+        self.split_at(end);
+        for vma in self.vmas.iter_mut().filter(|vma| vma.end > start && vma.start < end) {
+            vma.perm = perm;
+        }
+        page_table.protect_pages(VirtualAddr::new(start), (end - start) as usize, perm | PTEPermission::user());
+        Ok(())
+    }
+
+    // The code, heap and stack areas cannot be changed by munmap and mprotect
+    fn check_mmap_only(&self, start: u64, end: u64) -> Result<(), Errno> {
+        let other = self
+            .vmas
+            .iter()
+            .any(|vma| vma.overlaps(start, end) && !matches!(vma.kind, VmaKind::Mmap | VmaKind::Shm { .. }));
+        if other {
+            Err(Errno::EINVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Map `len` bytes of the object `id` from the page `offset` at `start`, the range must be free
+    pub fn map_shm(
+        &mut self,
+        page_table: &mut PageTable,
+        id: usize,
+        offset: usize,
+        start: u64,
+        len: u64,
+        perm: PTEPermission,
+    ) -> Result<(), Errno> {
+        let frames = shm_attach(id, offset, len as usize / PAGE_SIZE)?;
+        for (i, &frame) in frames.iter().enumerate() {
+            let va = VirtualAddr::new(start + (i * PAGE_SIZE) as u64);
+            let pa = PhysicalAddr::new(frame);
+            if page_table.try_map_pages(va, pa, PAGE_SIZE, perm | PTEPermission::user(), 0).is_err() {
+                if i > 0 {
+                    page_table.unmap_pages(VirtualAddr::new(start), i * PAGE_SIZE, false);
+                }
+                shm_detach(id);
+                return Err(Errno::ENOMEM);
+            }
+        }
+        self.insert(Vma {
+            start,
+            end: start + len,
+            perm,
+            kind: VmaKind::Shm { id },
+        });
+        Ok(())
+    }
+
+    // Map a private copy of `len` bytes of the object `id` from the page `offset` at `start` (free)
+    // The pages are copied now, a later change of the object is not seen
+    pub fn map_shm_copy(
+        &mut self,
+        page_table: &mut PageTable,
+        id: usize,
+        offset: usize,
+        start: u64,
+        len: u64,
+        perm: PTEPermission,
+    ) -> Result<(), Errno> {
+        let frames = shm_attach(id, offset, len as usize / PAGE_SIZE)?;
+        self.reclaim_all(page_table);
+        for (i, &frame) in frames.iter().enumerate() {
+            let va = VirtualAddr::new(start + (i * PAGE_SIZE) as u64);
+            if let Err(e) = map_copied_page(page_table, va, perm | PTEPermission::user(), frame) {
+                if i > 0 {
+                    page_table.unmap_pages(VirtualAddr::new(start), i * PAGE_SIZE, true);
+                }
+                shm_detach(id);
+                return Err(e);
+            }
+        }
+        shm_detach(id);
+        self.insert(Vma {
+            start,
+            end: start + len,
+            perm,
+            kind: VmaKind::Mmap,
+        });
+        Ok(())
+    }
+
+    // Unmap the shared memory objects before the pages of the process are freed (when it exits or execs)
+    pub fn unmap_all_shm(&mut self, page_table: &mut PageTable) {
+        let (removed, kept) = core::mem::take(&mut self.vmas)
+            .into_iter()
+            .partition(|vma| matches!(vma.kind, VmaKind::Shm { .. }));
+        self.vmas = kept;
+        for vma in removed {
+            unmap_vma(page_table, &vma);
+        }
+    }
+
+    // Reclaim from the other processes, then from this one which is locked by the caller
+    fn reclaim_all(&mut self, page_table: &mut PageTable) {
+        reclaim();
+        self.reclaim(page_table);
+    }
+
+    // Write cold pages of this address space to the swap if there are few free pages
+    // The pages whose accessed bit is set get a second chance, the bit is cleared and they are skipped
+    // The process must not be running on another hart (its TLB would keep the pages)
+    pub fn reclaim(&mut self, page_table: &mut PageTable) {
+        if !swap_enabled() || PAGE_ALLOCATOR.free_count() >= SWAP_LOW_WATERMARK {
+            return;
+        }
+        let pages: u64 = self
+            .vmas
+            .iter()
+            .filter(|vma| vma.is_swappable())
+            .map(|vma| (vma.end - vma.start) / PAGE_SIZE as u64)
+            .sum();
+        // Two turns so that the pages which have been skipped can be taken
+        for _ in 0..2 * pages {
+            if PAGE_ALLOCATOR.free_count() >= SWAP_HIGH_WATERMARK {
+                return;
+            }
+            let Some(page) = self.next_swappable_page() else {
+                return;
+            };
+            self.clock_hand = page;
+            let va = VirtualAddr::new(page);
+            if page_table.translate(&va).is_none() || page_table.test_and_clear_accessed(&va) {
+                continue;
+            }
+            let (pa, _) = page_table.translate(&va).unwrap();
+            let Some(slot) = swap_out(*pa.get() as *const u8) else {
+                return;
+            };
+            let pa = page_table.set_swapped(&va, slot).unwrap();
+            // The process may be running on this hart
+            unsafe {
+                riscv::asm::sfence_vma_all();
+            }
+            PAGE_ALLOCATOR.kfree(NonNull::new(*pa.get() as *mut u8).unwrap());
+        }
+    }
+
+    // The page after the clock hand in a swappable area, wrapping around to the first area
+    fn next_swappable_page(&self) -> Option<u64> {
+        let next = self.clock_hand + PAGE_SIZE as u64;
+        let mut swappable = self.vmas.iter().filter(|vma| vma.is_swappable() && vma.start < vma.end);
+        match swappable.clone().find(|vma| vma.end > next) {
+            Some(vma) => Some(core::cmp::max(next, vma.start)),
+            None => swappable.next().map(|vma| vma.start),
+        }
+    }
+
+    // Cut the area containing `va` in two at `va` (page aligned)
+    fn split_at(&mut self, va: u64) {
+        if let Some(i) = self.vmas.iter().position(|vma| vma.start < va && va < vma.end) {
+            let mut upper = self.vmas[i].clone();
+            upper.start = va;
+            if let VmaKind::Shm { id } = upper.kind {
+                shm_dup_mapping(id);
+            }
+            self.vmas[i].end = va;
+            self.vmas.insert(i + 1, upper);
+        }
+    }
+
+    // Fails with EFAULT if [va, va + len) is not covered by areas allowing `access`, nothing is mapped
+    pub fn check_access(&self, va: u64, len: usize, access: Access) -> Result<(), Errno> {
+        let end = va + len as u64;
+        let mut addr = va;
+        while addr < end {
+            let vma = self.find(addr).filter(|vma| vma.allows(access)).ok_or(Errno::EFAULT)?;
+            addr = vma.end;
+        }
+        Ok(())
+    }
+
+    // Map the pages of [va, va + len) not mapped yet, used before the kernel accesses the user memory
+    // Stops with EFAULT at the first page outside of an area allowing `access`
+    pub fn fault_in(&mut self, page_table: &mut PageTable, va: u64, len: usize, access: Access) -> Result<(), Errno> {
+        if len == 0 {
+            return Ok(());
+        }
+        // Reclaiming between two pages could take back a page of the range
+        self.reclaim_all(page_table);
+        let end = va + len as u64;
+        let mut page = page_round_down(va);
+        while page < end {
+            if page_table.translate(&VirtualAddr::new(page)).is_none() {
+                self.map_page(page_table, page, access)?;
+            }
+            page += PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+}
+
+fn map_zeroed_page(page_table: &mut PageTable, va: VirtualAddr, perm: PTEPermission) -> Result<(), Errno> {
+    // kalloc gives a zeroed page, identity mapped in the kernel
+    let page = PAGE_ALLOCATOR.kalloc().map_err(|_| Errno::ENOMEM)?;
+    let pa = PhysicalAddr::new(usize::from(page.addr()) as u64);
+    if page_table.try_map_pages(va, pa, PAGE_SIZE, perm, 0).is_err() {
+        PAGE_ALLOCATOR.kfree(page);
+        return Err(Errno::ENOMEM);
+    }
+    Ok(())
+}
+
+// The kernel memory is identity mapped so `frame` can be read directly
+fn map_copied_page(page_table: &mut PageTable, va: VirtualAddr, perm: PTEPermission, frame: u64) -> Result<(), Errno> {
+    let page = PAGE_ALLOCATOR.kalloc().map_err(|_| Errno::ENOMEM)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(frame as *const u8, page.as_ptr(), PAGE_SIZE);
+    }
+    let pa = PhysicalAddr::new(usize::from(page.addr()) as u64);
+    if page_table.try_map_pages(va, pa, PAGE_SIZE, perm, 0).is_err() {
+        PAGE_ALLOCATOR.kfree(page);
+        return Err(Errno::ENOMEM);
+    }
+    Ok(())
+}
+
+fn map_swapped_page(page_table: &mut PageTable, va: VirtualAddr, perm: PTEPermission, slot: u64) -> Result<(), Errno> {
+    let page = PAGE_ALLOCATOR.kalloc().map_err(|_| Errno::ENOMEM)?;
+    let pa = PhysicalAddr::new(usize::from(page.addr()) as u64);
+    if let Err(e) = swap_in(slot, page.as_ptr()) {
+        PAGE_ALLOCATOR.kfree(page);
+        return Err(e);
+    }
+    // The entry of the swapped page is replaced
+    if page_table.try_map_pages(va, pa, PAGE_SIZE, perm, 0).is_err() {
+        PAGE_ALLOCATOR.kfree(page);
+        return Err(Errno::ENOMEM);
+    }
+    Ok(())
+}
+
+fn unmap_vma(page_table: &mut PageTable, vma: &Vma) {
+    let len = (vma.end - vma.start) as usize;
+    match vma.kind {
+        VmaKind::Shm { id } => {
+            page_table.unmap_pages(VirtualAddr::new(vma.start), len, false);
+            shm_detach(id);
+        }
+        _ => {
+            free_swapped_range(page_table, vma.start, vma.end);
+            page_table.unmap_pages(VirtualAddr::new(vma.start), len, true);
+        }
+    }
+}
+
+// Give back the swap slots of the pages of [start, end) before they are unmapped
+fn free_swapped_range(page_table: &mut PageTable, start: u64, end: u64) {
+    if !swap_enabled() {
+        return;
+    }
+    for page in (start..end).step_by(PAGE_SIZE) {
+        if let Some(slot) = page_table.take_swapped(&VirtualAddr::new(page)) {
+            free_slot(slot);
+        }
+    }
+}
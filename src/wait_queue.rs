@@ -0,0 +1,72 @@
+use crate::cpu::get_cpu;
+use crate::proc::{Proc, ProcState};
+use crate::scheduler::{sched, wake};
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use spinlock::SpinLock;
+
+// Processes sleeping until an event happens (a child exiting, some data available...)
+// The sleeping processes are owned by the queue and are given back to the scheduler when woken up
+pub struct WaitQueue {
+    lock: SpinLock<()>,
+    // Only accessed with `lock` held
+    waiters: UnsafeCell<VecDeque<Proc>>,
+}
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            lock: SpinLock::new("wait_queue", ()),
+            waiters: UnsafeCell::new(VecDeque::new()),
+        }
+    }
+
+    // Put the current process to sleep and release `guard` (the lock protecting the condition waited for)
+    // The queue is locked before releasing `guard` and until the process is in the queue,
+    // so a wake up done after the condition has been changed cannot be lost
+    // The queue must outlive the sleeping process
+    pub fn sleep<G>(&self, guard: G) {
+        let queue_guard = self.lock.lock();
+        drop(guard);
+
+        {
+            let mut cpu = get_cpu();
+            let proc = cpu.proc.as_mut().unwrap();
+            proc.state = ProcState::Sleeping;
+            proc.wait_queue = Some(NonNull::from(self));
+        }
+        // Released by `park` once the process is not running anymore
+        core::mem::forget(queue_guard);
+        sched();
+    }
+
+    // Called by the scheduler once the process has switched out, with the lock taken in `sleep`
+    pub(crate) unsafe fn park(&self, proc: Proc) {
+        (*self.waiters.get()).push_back(proc);
+        self.lock.force_unlock();
+    }
+
+    // Returns true if a process has been woken up
+    pub fn wake_one(&self) -> bool {
+        let _guard = self.lock.lock();
+        let waiters = unsafe { &mut *self.waiters.get() };
+        match waiters.pop_front() {
+            Some(proc) => {
+                wake(proc);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns the number of processes woken up
+    pub fn wake_all(&self) -> usize {
+        let _guard = self.lock.lock();
+        let waiters = unsafe { &mut *self.waiters.get() };
+        let count = waiters.len();
+        waiters.drain(..).for_each(wake);
+        count
+    }
+}